@@ -0,0 +1,183 @@
+use std::{sync::OnceLock, time::Duration};
+use futures::future::BoxFuture;
+use reqwest::RequestBuilder;
+use serde::{Serialize, Deserialize};
+
+use crate::{errors::AppError, quota::record_rate_limit, models::ResultSet, constants::{BRAVE_SEARCH_BASE, MOJEEK_SEARCH_BASE, TEXTSURF_SEARCH_BASE, DEFAULT_PROVIDER_TIMEOUT_MS}, options::{BraveSearchOptions, SearchProvider, SearchProviderMode}, utils::build_query_string};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BackendCapabilities {
+  pub paging: bool,
+  pub news: bool,
+  pub country: bool,
+  pub language: bool,
+}
+
+/// Send an upstream request and decode the JSON body, classifying HTTP failures
+pub async fn fetch_json(provider: SearchProvider, meter: &str, request: RequestBuilder) -> Result<serde_json::Value, AppError> {
+  let resp = request.send().await.map_err(|e| AppError::from_request(provider, e))?;
+  record_rate_limit(meter, resp.headers());
+  let retry_after = resp.headers().get(reqwest::header::RETRY_AFTER).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+  if let Some(error) = AppError::from_status(provider, resp.status(), retry_after) {
+    return Err(error);
+  }
+  resp.json::<serde_json::Value>().await.map_err(|e| AppError::from_request(provider, e))
+}
+
+/// Remote search engine that can be queried with the common search options
+pub trait SearchBackend: Send + Sync {
+  fn name(&self) -> &'static str;
+
+  fn provider(&self) -> SearchProvider;
+
+  fn capabilities(&self) -> BackendCapabilities;
+
+  fn search<'a>(&'a self, options: &'a BraveSearchOptions) -> BoxFuture<'a, Result<ResultSet, AppError>>;
+
+  /// Deadline for this backend, e.g. BRAVE_TIMEOUT_MS, falling back to PROVIDER_TIMEOUT_MS
+  fn timeout(&self) -> Duration {
+    let env_key = format!("{}_TIMEOUT_MS", self.name().to_uppercase());
+    let ms_str = dotenv::var(env_key).or_else(|_| dotenv::var("PROVIDER_TIMEOUT_MS")).unwrap_or_default();
+    Duration::from_millis(ms_str.parse::<u64>().unwrap_or(DEFAULT_PROVIDER_TIMEOUT_MS))
+  }
+}
+
+pub struct BraveBackend;
+
+impl SearchBackend for BraveBackend {
+  fn name(&self) -> &'static str {
+    "brave"
+  }
+
+  fn provider(&self) -> SearchProvider {
+    SearchProvider::Brave
+  }
+
+  fn capabilities(&self) -> BackendCapabilities {
+    BackendCapabilities {
+      paging: true,
+      news: true,
+      country: true,
+      language: true,
+    }
+  }
+
+  fn search<'a>(&'a self, options: &'a BraveSearchOptions) -> BoxFuture<'a, Result<ResultSet, AppError>> {
+    Box::pin(async move {
+      let uri = [BRAVE_SEARCH_BASE, &build_query_string(&options.to_tuples())].concat();
+      let api_key = dotenv::var("BRAVE_SEARCH").unwrap_or_default();
+      let client = reqwest::Client::new();
+      let json = fetch_json(self.provider(), self.name(), client.get(&uri).header("X-Subscription-Token", &api_key)).await?;
+      Ok(ResultSet::new(&json, options))
+    })
+  }
+}
+
+pub struct MojeekBackend;
+
+impl SearchBackend for MojeekBackend {
+  fn name(&self) -> &'static str {
+    "mojeek"
+  }
+
+  fn provider(&self) -> SearchProvider {
+    SearchProvider::Mojeek
+  }
+
+  fn capabilities(&self) -> BackendCapabilities {
+    BackendCapabilities {
+      paging: false,
+      news: false,
+      country: true,
+      language: true,
+    }
+  }
+
+  fn search<'a>(&'a self, options: &'a BraveSearchOptions) -> BoxFuture<'a, Result<ResultSet, AppError>> {
+    Box::pin(async move {
+      let uri = [MOJEEK_SEARCH_BASE, &build_query_string(&options.to_mojeek_tuples())].concat();
+      let client = reqwest::Client::new();
+      let json = fetch_json(self.provider(), self.name(), client.get(&uri)).await?;
+      Ok(ResultSet::new_from_mojeek(&json, options))
+    })
+  }
+}
+
+/// TextSurf full-text index, self-hosted at TEXTSURF_SEARCH_BASE
+pub struct TextSurfBackend;
+
+impl SearchBackend for TextSurfBackend {
+  fn name(&self) -> &'static str {
+    "textsurf"
+  }
+
+  fn provider(&self) -> SearchProvider {
+    SearchProvider::Text
+  }
+
+  fn capabilities(&self) -> BackendCapabilities {
+    BackendCapabilities {
+      paging: true,
+      news: false,
+      country: true,
+      language: true,
+    }
+  }
+
+  fn search<'a>(&'a self, options: &'a BraveSearchOptions) -> BoxFuture<'a, Result<ResultSet, AppError>> {
+    Box::pin(async move {
+      let base = dotenv::var("TEXTSURF_SEARCH_BASE").unwrap_or(TEXTSURF_SEARCH_BASE.to_owned());
+      let uri = [base.as_str(), &build_query_string(&options.to_textsurf_tuples())].concat();
+      let api_key = dotenv::var("TEXTSURF_SEARCH").unwrap_or_default();
+      let client = reqwest::Client::new();
+      let json = fetch_json(self.provider(), self.name(), client.get(&uri).header("X-API-Key", &api_key)).await?;
+      Ok(ResultSet::new_from_textsurf(&json, options))
+    })
+  }
+}
+
+/// Ordered list of available backends. The first backend selected for a mode
+/// provides the base result set that the others are merged into.
+pub struct BackendRegistry {
+  backends: Vec<Box<dyn SearchBackend>>,
+}
+
+impl BackendRegistry {
+  pub fn new() -> Self {
+    BackendRegistry {
+      backends: Vec::new(),
+    }
+  }
+
+  pub fn register(&mut self, backend: Box<dyn SearchBackend>) -> &mut Self {
+    self.backends.push(backend);
+    self
+  }
+
+  pub fn for_mode(&self, mode: SearchProviderMode) -> Vec<&dyn SearchBackend> {
+    self.backends.iter().filter(|b| mode.includes(b.provider())).map(|b| b.as_ref()).collect()
+  }
+
+  /// Backends selected by the mode, split into those that can serve the requested page
+  /// and those skipped because they cannot page
+  pub fn for_options(&self, options: &BraveSearchOptions) -> (Vec<&dyn SearchBackend>, Vec<&dyn SearchBackend>) {
+    let first_page = options.offset.unwrap_or(0) == 0;
+    self.for_mode(options.mode).into_iter().partition(|b| first_page || b.capabilities().paging)
+  }
+}
+
+impl Default for BackendRegistry {
+  fn default() -> Self {
+    let mut registry = BackendRegistry::new();
+    registry
+      .register(Box::new(BraveBackend))
+      .register(Box::new(MojeekBackend))
+      .register(Box::new(TextSurfBackend));
+    registry
+  }
+}
+
+pub fn backend_registry() -> &'static BackendRegistry {
+  static REGISTRY: OnceLock<BackendRegistry> = OnceLock::new();
+  REGISTRY.get_or_init(BackendRegistry::default)
+}
@@ -1,22 +1,11 @@
-use redis::{Commands, RedisResult, Connection, Client};
 use chrono::{Local, Duration};
-use crate::{models::*, exclusions::UrlPattern};
-
-pub fn  redis_client() -> RedisResult<Connection> {
-  let client = Client::open("redis://127.0.0.1/")?;
-  client.get_connection()
-}
+use crate::{models::*, exclusions::{UrlPattern, DEFAULT_PROFILE}, stores::CacheStore};
 
 pub fn get_timestamp() -> i64 {
   let dt = Local::now();
   dt.timestamp()
 }
 
-pub fn seconds_ago(ts: i64) -> i64 {
-  let now_ts = get_timestamp();
-  now_ts - ts
-}
-
 pub fn get_max_seconds(def_secs: i64) -> i64 {
   let max_seconds_limit: u32 = 7 * 24 * 60 * 60;
   let sec_str = dotenv::var("MAX_SEARCH_SECS").unwrap_or(def_secs.to_string());
@@ -31,6 +20,22 @@ pub fn get_max_seconds(def_secs: i64) -> i64 {
   }
 }
 
+/// Hard limit for serving stale search results while they are refreshed,
+/// from MAX_SEARCH_STALE_SECS and never shorter than the soft limit
+pub fn get_max_stale_seconds(soft_secs: i64) -> i64 {
+  let max_seconds_limit: i64 = 7 * 24 * 60 * 60;
+  let sec_str = dotenv::var("MAX_SEARCH_STALE_SECS").unwrap_or_default();
+  let hard_secs = sec_str.parse::<i64>().unwrap_or(soft_secs * 24);
+  hard_secs.clamp(soft_secs, max_seconds_limit.max(soft_secs))
+}
+
+/// Lifetime of results missing one or more providers, from MAX_PARTIAL_SEARCH_SECS (default 300).
+/// These expire before they can go stale, so the missing providers are retried without a refetch on every hit.
+pub fn get_max_partial_seconds(soft_secs: i64) -> i64 {
+  let sec_str = dotenv::var("MAX_PARTIAL_SEARCH_SECS").unwrap_or_default();
+  sec_str.parse::<i64>().unwrap_or(300).clamp(1, soft_secs.max(1))
+}
+
 pub fn get_max_suggest_seconds(def_secs: i64) -> i64 {
   let max_seconds_limit: u32 = 13 * 7 * 24 * 60 * 60;
   let sec_str = dotenv::var("MAX_SUGGEST_SECS").unwrap_or(def_secs.to_string());
@@ -45,36 +50,46 @@ pub fn get_max_suggest_seconds(def_secs: i64) -> i64 {
   }
 }
 
-fn redis_get_opt_string(key: &str) -> Option<String> {
-  if let Ok(mut connection) =  redis_client() {
-      let result: String = connection.get(key.to_owned()).unwrap_or("".to_owned());
-      Some(result)
-  } else {
+async fn cache_get_opt_string(store: &dyn CacheStore, key: &str) -> Option<String> {
+  match store.get(key).await {
+    Ok(result) => Some(result.unwrap_or_default()),
+    Err(error) => {
+      tracing::debug!("{} cache read failed: {}", store.name(), error);
       None
+    }
   }
 }
 
-
-pub fn  redis_set_results(key: &str, result: &ResultSet) -> Option<ResultSet> {
-  if let Ok(mut connection) =  redis_client() {
-      match serde_json::to_string(result) {
-        Ok(value) => match connection.set::<String,String,String>(key.to_string(), value) {
-          Ok(_result) => Some(result.to_owned()),
-          Err(_error) => None,
-        },
-        _ => None
-      }
-  } else {
-    None
+async fn cache_set_json<T: serde::Serialize + Clone>(store: &dyn CacheStore, key: &str, result: &T, ttl: Option<std::time::Duration>) -> Option<T> {
+  match serde_json::to_string(result) {
+    Ok(value) => match store.set(key, value, ttl).await {
+      Ok(_result) => Some(result.to_owned()),
+      Err(_error) => None,
+    },
+    _ => None
   }
 }
 
-pub fn redis_get_results(key: &str, age: Duration) -> Option<ResultSet> {
-  if let Some(result) = redis_get_opt_string(key) {
-      if result.len() > 0 {
+/// Entries expire in the store after the stale limit, so old keys are evicted without a read
+pub async fn cache_set_results(store: &dyn CacheStore, key: &str, result: &ResultSet, age: Duration) -> Option<ResultSet> {
+  let ttl = get_max_stale_seconds(get_max_seconds(age.num_seconds())).max(1) as u64;
+  cache_set_json(store, key, result, Some(std::time::Duration::from_secs(ttl))).await
+}
+
+pub async fn cache_set_partial_results(store: &dyn CacheStore, key: &str, result: &ResultSet, age: Duration) -> Option<ResultSet> {
+  let ttl = get_max_partial_seconds(get_max_seconds(age.num_seconds())) as u64;
+  cache_set_json(store, key, result, Some(std::time::Duration::from_secs(ttl))).await
+}
+
+pub async fn cache_get_results(store: &dyn CacheStore, key: &str, age: Duration) -> Option<ResultSet> {
+  if let Some(result) = cache_get_opt_string(store, key).await {
+      if !result.is_empty() {
           let mut data: ResultSet = serde_json::from_str(&result).unwrap_or(ResultSet::empty());
+          // results past the soft limit are still served, flagged as stale, until the hard limit
           let max_secs = get_max_seconds(age.num_seconds());
-          if data.retrieved_age() < max_secs {
+          let retrieved_age = data.retrieved_age();
+          if retrieved_age < get_max_stale_seconds(max_secs) {
+            data.stale = retrieved_age >= max_secs;
             data.set_cached();
             Some(data)
           } else {
@@ -88,24 +103,14 @@ pub fn redis_get_results(key: &str, age: Duration) -> Option<ResultSet> {
   }
 }
 
-
-pub fn  redis_set_suggest_results(key: &str, result: &AutoSuggestResultSet) -> Option<AutoSuggestResultSet> {
-  if let Ok(mut connection) =  redis_client() {
-      match serde_json::to_string(result) {
-        Ok(value) => match connection.set::<String,String,String>(key.to_string(), value) {
-          Ok(_result) => Some(result.to_owned()),
-          Err(_error) => None,
-        },
-        _ => None
-      }
-  } else {
-    None
-  }
+pub async fn cache_set_suggest_results(store: &dyn CacheStore, key: &str, result: &AutoSuggestResultSet, age: Duration) -> Option<AutoSuggestResultSet> {
+  let ttl = get_max_suggest_seconds(age.num_seconds()).max(1) as u64;
+  cache_set_json(store, key, result, Some(std::time::Duration::from_secs(ttl))).await
 }
 
-pub fn redis_get_suggest_results(key: &str, age: Duration) -> Option<AutoSuggestResultSet> {
-  if let Some(result) = redis_get_opt_string(key) {
-      if result.len() > 0 {
+pub async fn cache_get_suggest_results(store: &dyn CacheStore, key: &str, age: Duration) -> Option<AutoSuggestResultSet> {
+  if let Some(result) = cache_get_opt_string(store, key).await {
+      if !result.is_empty() {
           let mut data: AutoSuggestResultSet = serde_json::from_str(&result).unwrap_or(AutoSuggestResultSet::empty());
           let max_secs = get_max_suggest_seconds(age.num_seconds());
           if data.retrieved_age() < max_secs {
@@ -122,23 +127,22 @@ pub fn redis_get_suggest_results(key: &str, age: Duration) -> Option<AutoSuggest
   }
 }
 
-pub fn  redis_set_exclusions(result: &[UrlPattern]) -> Option<Vec<UrlPattern>> {
-  if let Ok(mut connection) =  redis_client() {
-      match serde_json::to_string(result) {
-        Ok(value) => match connection.set::<String,String,String>("url_pattern_exclusion_list".to_string(), value) {
-          Ok(_result) => Some(result.to_owned()),
-          Err(_error) => None,
-        },
-        _ => None
-      }
+/// Each exclusion profile is cached under its own key, the default profile under the original one
+fn exclusions_key(profile: &str) -> String {
+  if profile == DEFAULT_PROFILE {
+    "url_pattern_exclusion_list".to_owned()
   } else {
-    None
+    format!("url_pattern_exclusion_list_{}", profile)
   }
 }
 
-pub fn redis_get_exclusions() -> Vec<UrlPattern> {
-  if let Some(result) = redis_get_opt_string("url_pattern_exclusion_list") {
-      if result.len() > 0 {
+pub async fn cache_set_exclusions(store: &dyn CacheStore, profile: &str, result: &[UrlPattern]) -> Option<Vec<UrlPattern>> {
+  cache_set_json(store, &exclusions_key(profile), &result.to_vec(), None).await
+}
+
+pub async fn cache_get_exclusions(store: &dyn CacheStore, profile: &str) -> Vec<UrlPattern> {
+  if let Some(result) = cache_get_opt_string(store, &exclusions_key(profile)).await {
+      if !result.is_empty() {
           let items: Vec<UrlPattern> = serde_json::from_str(&result).unwrap_or(vec![]);
           items
       } else {
@@ -147,4 +151,4 @@ pub fn redis_get_exclusions() -> Vec<UrlPattern> {
   } else {
     vec![]
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,152 @@
+use std::{fs, net::SocketAddr};
+use axum::{
+  extract::{ConnectInfo, State},
+  http::{HeaderMap, Request},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+use slug::slugify;
+
+use crate::{errors::AppError, options::SearchProviderMode, quota::increment_usage, rate_limit::client_ip, state::AppState, stores::CacheStore};
+
+/// Cache key holding the client key list when it is not loaded from a file
+const CLIENT_KEY_LIST_KEY: &str = "client_key_list";
+
+/// Key issued to a client of this service, not to be confused with the upstream API keys
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientKey {
+  pub key: String,
+  pub name: Option<String>,
+  pub modes: Option<Vec<String>>, // allowed search modes, all if omitted
+  pub quota: Option<u64>, // requests per calendar month
+  pub profile: Option<String>, // exclusion profile applied to every search
+}
+
+impl ClientKey {
+  /// Usage meter for the client, named so that the key itself is not written to the store
+  fn meter(&self) -> String {
+    let id = match self.name.as_ref() {
+      Some(name) if !name.trim().is_empty() => slugify(name),
+      _ => slugify(self.key.chars().take(8).collect::<String>()),
+    };
+    format!("client_{}", id)
+  }
+
+  /// Unknown mode names never match, so a typo cannot grant the core mode
+  pub fn allows_mode(&self, mode: SearchProviderMode) -> bool {
+    self.modes.as_ref().is_none_or(|modes| modes.iter().any(|key| SearchProviderMode::parse_key(key) == Some(mode)))
+  }
+
+  /// Check the requested mode, if any, and count the request against the monthly quota
+  pub async fn authorize(&self, store: &dyn CacheStore, mode: Option<SearchProviderMode>) -> Result<(), AppError> {
+    if let Some(mode) = mode {
+      if !self.allows_mode(mode) {
+        return Err(AppError::ModeNotAllowed(mode.param_key().unwrap_or("core").to_owned()));
+      }
+    }
+    let used = increment_usage(store, &self.meter()).await;
+    match self.quota {
+      Some(quota) if used > quota => Err(AppError::ClientQuotaExhausted),
+      _ => Ok(())
+    }
+  }
+}
+
+/// Client keys from the PATH_TO_CLIENT_KEYS file, or from the cache store if no file is set.
+/// Keys are only enforced with REQUIRE_CLIENT_KEY=1.
+pub struct ClientKeys {
+  required: bool,
+  file_keys: Vec<ClientKey>,
+}
+
+impl ClientKeys {
+  pub fn from_env() -> Self {
+    let required = dotenv::var("REQUIRE_CLIENT_KEY").unwrap_or_default() == "1";
+    let file_keys = match dotenv::var("PATH_TO_CLIENT_KEYS") {
+      Ok(fpath) => load_client_keys(&fpath),
+      Err(_) => vec![],
+    };
+    if required {
+      tracing::info!("client keys required, {} loaded from file", file_keys.len());
+    }
+    ClientKeys { required, file_keys }
+  }
+
+  pub async fn find(&self, store: &dyn CacheStore, key: &str) -> Option<ClientKey> {
+    let keys = if !self.file_keys.is_empty() {
+      self.file_keys.clone()
+    } else {
+      match store.get(CLIENT_KEY_LIST_KEY).await {
+        Ok(Some(value)) => serde_json::from_str::<Vec<ClientKey>>(&value).unwrap_or_default(),
+        _ => vec![],
+      }
+    };
+    keys.into_iter().find(|client| !client.key.is_empty() && client.key == key)
+  }
+}
+
+fn load_client_keys(fpath: &str) -> Vec<ClientKey> {
+  match fs::read_to_string(fpath) {
+    Ok(contents) => {
+      let keys = serde_json::from_str::<Vec<ClientKey>>(&contents).unwrap_or_else(|error| {
+        tracing::error!("invalid client key file {}: {}", fpath, error);
+        vec![]
+      });
+      for client in keys.iter() {
+        let unknown = client.modes.iter().flatten().filter(|key| SearchProviderMode::parse_key(key).is_none()).collect::<Vec<_>>();
+        if !unknown.is_empty() {
+          tracing::warn!("client key {} in {} has unknown modes, ignored: {:?}", client.meter(), fpath, unknown);
+        }
+      }
+      keys
+    },
+    Err(error) => {
+      tracing::error!("cannot read client key file {}: {}", fpath, error);
+      vec![]
+    }
+  }
+}
+
+/// Client key sent in the X-Api-Key header or the key query parameter
+pub fn client_key_from_request(headers: &HeaderMap, query: Option<&str>) -> Option<String> {
+  if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+    return Some(key.to_owned());
+  }
+  query.unwrap_or("").split('&')
+    .filter_map(|pair| pair.split_once('='))
+    .find(|(k, _)| *k == "key")
+    .map(|(_, v)| urlencoding::decode(v).map(|d| d.into_owned()).unwrap_or(v.to_owned()))
+    .filter(|v| !v.is_empty())
+}
+
+/// Resolve the client key on /search and /suggest and attach it to the request.
+/// Unknown keys are always rejected, missing keys only when keys are required.
+pub async fn authenticate<B>(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, mut request: Request<B>, next: Next<B>) -> Response {
+  if !matches!(request.uri().path(), "/search" | "/suggest") {
+    return next.run(request).await;
+  }
+  match client_key_from_request(request.headers(), request.uri().query()) {
+    Some(key) => {
+      // failed attempts are limited per IP so that keys cannot be guessed at full speed
+      let ip = client_ip(request.headers(), &addr);
+      if let Err(error) = state.limiter.check_failures(&ip) {
+        return error.into_response();
+      }
+      match state.clients.find(state.cache.as_ref(), &key).await {
+        Some(client) => {
+          request.extensions_mut().insert(client);
+          next.run(request).await
+        },
+        None => {
+          state.limiter.record_failure(&ip);
+          AppError::InvalidClientKey.into_response()
+        },
+      }
+    },
+    None if state.clients.required => AppError::MissingClientKey.into_response(),
+    None => next.run(request).await,
+  }
+}
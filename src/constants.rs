@@ -1,10 +1,14 @@
-pub const BRAVE_SEARCH_BASE: &'static str = "https://api.search.brave.com/res/v1/web/search";
+pub const BRAVE_SEARCH_BASE: &str = "https://api.search.brave.com/res/v1/web/search";
 
-pub const BRAVE_SUGGEST_BASE: &'static str = "https://api.search.brave.com/res/v1/suggest/search";
+pub const BRAVE_SUGGEST_BASE: &str = "https://api.search.brave.com/res/v1/suggest/search";
 
-pub const MOJEEK_SEARCH_BASE: &'static str = "https://www.mojeek.com/search";
+pub const MOJEEK_SEARCH_BASE: &str = "https://www.mojeek.com/search";
 
-pub const COUNTRY_CODES: [&'static str; 36] = [
+pub const TEXTSURF_SEARCH_BASE: &str = "http://127.0.0.1:8090/search";
+
+pub const DEFAULT_PROVIDER_TIMEOUT_MS: u64 = 5000;
+
+pub const COUNTRY_CODES: [&str; 36] = [
   "AR", "AU", "AT", "BE", "BR",
   "CA", "CL", "DK", "FI", "FR",
   "DE", "HK", "IN", "ID", "IT",
@@ -21,9 +25,5 @@ pub fn match_country_code(key: &str) -> Option<String> {
     "UK" => "GB",
     _ => cc.as_str()
   };
-  if let Some(cc_k) = COUNTRY_CODES.into_iter().find(|k| *k == cc_key) {
-    Some(cc_k.to_string())
-  } else {
-    None
-  }
+  COUNTRY_CODES.into_iter().find(|k| *k == cc_key).map(|cc_k| cc_k.to_string())
 }
\ No newline at end of file
@@ -0,0 +1,191 @@
+use std::fmt;
+use serde_json::json;
+use axum::{
+  extract::rejection::{JsonRejection, QueryRejection},
+  response::{IntoResponse, Response},
+  http::{header, StatusCode},
+  Json,
+};
+use redis::RedisError;
+
+use crate::options::SearchProvider;
+
+/// Application error mapped to an HTTP status and a machine-readable code.
+/// Messages are kept as strings so that errors can be cloned and shared.
+#[derive(Debug, Clone)]
+pub enum AppError {
+  MissingQuery,
+  InvalidParams(String),
+  InvalidBody(String),
+  UpstreamAuth(SearchProvider),
+  UpstreamRateLimited(SearchProvider, Option<u64>),
+  Upstream(SearchProvider, String),
+  UpstreamTimeout(SearchProvider),
+  QuotaExhausted(Vec<SearchProvider>),
+  CacheUnavailable(String),
+  Forbidden,
+  RateLimited(u64),
+  MissingClientKey,
+  InvalidClientKey,
+  ModeNotAllowed(String),
+  ClientQuotaExhausted,
+  InvalidPattern(String),
+  PatternExists(String),
+  PatternNotFound(String),
+  PatternsNotSaved(String),
+  PatternsNotLoaded(String),
+  UnknownProfile(String),
+  Internal(String),
+}
+
+impl AppError {
+  pub fn from_request(provider: SearchProvider, error: reqwest::Error) -> Self {
+    if error.is_timeout() {
+      return AppError::UpstreamTimeout(provider);
+    }
+    if let Some(status) = error.status() {
+      if let Some(app_error) = AppError::from_status(provider, status, None) {
+        return app_error;
+      }
+    }
+    // strip the request URL, which may carry an API key
+    AppError::Upstream(provider, error.without_url().to_string())
+  }
+
+  /// Classify a non-success upstream status code
+  pub fn from_status(provider: SearchProvider, status: StatusCode, retry_after: Option<u64>) -> Option<Self> {
+    match status {
+      StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(AppError::UpstreamAuth(provider)),
+      StatusCode::TOO_MANY_REQUESTS => Some(AppError::UpstreamRateLimited(provider, retry_after)),
+      StatusCode::GATEWAY_TIMEOUT | StatusCode::REQUEST_TIMEOUT => Some(AppError::UpstreamTimeout(provider)),
+      _ if !status.is_success() => Some(AppError::Upstream(provider, format!("upstream responded with {}", status))),
+      _ => None
+    }
+  }
+
+  pub fn status_code(&self) -> StatusCode {
+    match self {
+      AppError::MissingQuery => StatusCode::BAD_REQUEST,
+      AppError::InvalidParams(_) | AppError::InvalidBody(_) => StatusCode::BAD_REQUEST,
+      AppError::UpstreamAuth(_) | AppError::Upstream(_, _) => StatusCode::BAD_GATEWAY,
+      AppError::UpstreamRateLimited(_, _) => StatusCode::TOO_MANY_REQUESTS,
+      AppError::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+      AppError::QuotaExhausted(_) => StatusCode::TOO_MANY_REQUESTS,
+      AppError::CacheUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+      AppError::Forbidden => StatusCode::FORBIDDEN,
+      AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+      AppError::MissingClientKey | AppError::InvalidClientKey => StatusCode::UNAUTHORIZED,
+      AppError::ModeNotAllowed(_) => StatusCode::FORBIDDEN,
+      AppError::ClientQuotaExhausted => StatusCode::TOO_MANY_REQUESTS,
+      AppError::InvalidPattern(_) => StatusCode::BAD_REQUEST,
+      AppError::PatternExists(_) => StatusCode::CONFLICT,
+      AppError::PatternNotFound(_) => StatusCode::NOT_FOUND,
+      AppError::PatternsNotSaved(_) => StatusCode::INTERNAL_SERVER_ERROR,
+      AppError::PatternsNotLoaded(_) => StatusCode::INTERNAL_SERVER_ERROR,
+      AppError::UnknownProfile(_) => StatusCode::BAD_REQUEST,
+      AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  pub fn code(&self) -> &'static str {
+    match self {
+      AppError::MissingQuery => "missing_query",
+      AppError::InvalidParams(_) => "invalid_params",
+      AppError::InvalidBody(_) => "invalid_body",
+      AppError::UpstreamAuth(_) => "upstream_auth_failed",
+      AppError::UpstreamRateLimited(_, _) => "upstream_rate_limited",
+      AppError::Upstream(_, _) => "upstream_error",
+      AppError::UpstreamTimeout(_) => "upstream_timeout",
+      AppError::QuotaExhausted(_) => "quota_exhausted",
+      AppError::CacheUnavailable(_) => "cache_unavailable",
+      AppError::Forbidden => "forbidden",
+      AppError::RateLimited(_) => "rate_limited",
+      AppError::MissingClientKey => "missing_client_key",
+      AppError::InvalidClientKey => "invalid_client_key",
+      AppError::ModeNotAllowed(_) => "mode_not_allowed",
+      AppError::ClientQuotaExhausted => "client_quota_exhausted",
+      AppError::InvalidPattern(_) => "invalid_pattern",
+      AppError::PatternExists(_) => "pattern_exists",
+      AppError::PatternNotFound(_) => "pattern_not_found",
+      AppError::PatternsNotSaved(_) => "patterns_not_saved",
+      AppError::PatternsNotLoaded(_) => "patterns_not_loaded",
+      AppError::UnknownProfile(_) => "unknown_profile",
+      AppError::Internal(_) => "internal_error",
+    }
+  }
+
+  pub fn provider(&self) -> Option<SearchProvider> {
+    match self {
+      AppError::UpstreamAuth(provider)
+      | AppError::UpstreamRateLimited(provider, _)
+      | AppError::Upstream(provider, _)
+      | AppError::UpstreamTimeout(provider) => Some(*provider),
+      _ => None
+    }
+  }
+}
+
+impl fmt::Display for AppError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AppError::MissingQuery => write!(f, "the q parameter is required"),
+      AppError::InvalidParams(message) => write!(f, "invalid query parameters: {}", message),
+      AppError::InvalidBody(message) => write!(f, "invalid request body: {}", message),
+      AppError::UpstreamAuth(provider) => write!(f, "{:?} rejected the API key", provider),
+      AppError::UpstreamRateLimited(provider, _) => write!(f, "{:?} rate limit exceeded", provider),
+      AppError::Upstream(provider, message) => write!(f, "{:?} request failed: {}", provider, message),
+      AppError::UpstreamTimeout(provider) => write!(f, "{:?} did not respond in time", provider),
+      AppError::QuotaExhausted(providers) => write!(f, "API budget used up for {:?}, only cached results are served", providers),
+      AppError::CacheUnavailable(message) => write!(f, "cache unavailable: {}", message),
+      AppError::Forbidden => write!(f, "a valid admin key is required"),
+      AppError::RateLimited(secs) => write!(f, "too many requests, retry in {} seconds", secs),
+      AppError::MissingClientKey => write!(f, "a client key is required in the X-Api-Key header or key parameter"),
+      AppError::InvalidClientKey => write!(f, "the client key is not valid"),
+      AppError::ModeNotAllowed(mode) => write!(f, "the client key does not allow the {} mode", mode),
+      AppError::ClientQuotaExhausted => write!(f, "the monthly request quota for this client key is used up"),
+      AppError::InvalidPattern(message) => write!(f, "invalid pattern: {}", message),
+      AppError::PatternExists(name) => write!(f, "a pattern named {} already exists", name),
+      AppError::PatternNotFound(name) => write!(f, "no pattern named {}", name),
+      AppError::PatternsNotSaved(message) => write!(f, "exclusion patterns not saved: {}", message),
+      AppError::PatternsNotLoaded(message) => write!(f, "exclusion patterns saved to file but not loaded: {}", message),
+      AppError::UnknownProfile(name) => write!(f, "no exclusion profile named {}", name),
+      AppError::Internal(message) => write!(f, "internal error: {}", message),
+    }
+  }
+}
+
+impl From<RedisError> for AppError {
+  fn from(error: RedisError) -> Self {
+    AppError::CacheUnavailable(error.to_string())
+  }
+}
+
+impl From<QueryRejection> for AppError {
+  fn from(rejection: QueryRejection) -> Self {
+    AppError::InvalidParams(rejection.body_text())
+  }
+}
+
+impl From<JsonRejection> for AppError {
+  fn from(rejection: JsonRejection) -> Self {
+    AppError::InvalidBody(rejection.body_text())
+  }
+}
+
+impl IntoResponse for AppError {
+  fn into_response(self) -> Response {
+    let body = json!({
+      "valid": false,
+      "error": {
+        "code": self.code(),
+        "message": self.to_string(),
+        "provider": self.provider(),
+      }
+    });
+    let mut response = (self.status_code(), Json(body)).into_response();
+    if let AppError::UpstreamRateLimited(_, Some(secs)) | AppError::RateLimited(secs) = self {
+      response.headers_mut().insert(header::RETRY_AFTER, secs.into());
+    }
+    response
+  }
+}
@@ -1,36 +1,783 @@
-use std::fs;
+use std::{collections::HashMap, fs, path::Path, sync::{Arc, Mutex, OnceLock, RwLock}, time::{Duration, SystemTime}};
+use regex::{RegexBuilder, RegexSet, RegexSetBuilder};
 use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+use slug::slugify;
+use url::Url;
 
-use crate::cache::{redis_get_exclusions, redis_set_exclusions};
+use crate::{models::SearchResult, similarity::strip_tags, cache::{cache_get_exclusions, cache_set_exclusions, get_timestamp}, errors::AppError, stores::CacheStore};
 
 
+/// What a rule does to the results it matches
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RuleAction {
+  #[default]
+  #[serde(rename = "exclude")]
+  Exclude,
+  #[serde(rename = "allow")]
+  Allow, // once a list has allow rules, only results matching one of them are kept
+  #[serde(rename = "boost")]
+  Boost,
+  #[serde(rename = "demote")]
+  Demote,
+}
+
+impl RuleAction {
+  fn is_exclude(&self) -> bool {
+    *self == RuleAction::Exclude
+  }
+
+  /// Multiplier applied to the weight of matching results, where a lower weight ranks higher
+  fn weight_multiplier(self, factor: Option<f64>) -> f64 {
+    let factor = factor.unwrap_or(DEFAULT_RULE_FACTOR);
+    match self {
+      RuleAction::Boost => 1.0 / factor,
+      RuleAction::Demote => factor,
+      _ => 1.0
+    }
+  }
+}
+
+/// Strength of boost and demote rules without a factor
+const DEFAULT_RULE_FACTOR: f64 = 2.0;
+
+/// Rule name reported for results removed because they match no allow rule
+const NOT_ALLOWED: &str = "not allowlisted";
+
+/// Part of a result a rule is tested against
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RuleTarget {
+  #[default]
+  #[serde(rename = "uri")]
+  Uri,
+  #[serde(rename = "host")]
+  Host,
+  #[serde(rename = "title")]
+  Title,
+  #[serde(rename = "summary")]
+  Summary,
+  #[serde(rename = "any")]
+  Any, // uri, title or summary
+}
+
+impl RuleTarget {
+  fn is_uri(&self) -> bool {
+    *self == RuleTarget::Uri
+  }
+
+  fn texts<'a>(self, fields: &'a ResultFields) -> Vec<&'a str> {
+    match self {
+      RuleTarget::Uri => vec![fields.uri],
+      RuleTarget::Host => vec![&fields.host],
+      RuleTarget::Title => vec![&fields.title],
+      RuleTarget::Summary => vec![&fields.summary],
+      RuleTarget::Any => vec![fields.uri, &fields.title, &fields.summary],
+    }
+  }
+}
+
+/// Fields of a result that rules can match, with HTML highlights removed from the text
+struct ResultFields<'a> {
+  uri: &'a str,
+  host: String,
+  title: String,
+  summary: String,
+}
+
+impl<'a> ResultFields<'a> {
+  fn new(row: &'a SearchResult) -> Self {
+    ResultFields {
+      uri: &row.uri,
+      host: Url::parse(&row.uri).ok().and_then(|url| url.host_str().map(|host| host.to_owned())).unwrap_or_default(),
+      title: strip_tags(&row.title),
+      summary: strip_tags(&row.summary),
+    }
+  }
+}
+
+fn is_false(value: &bool) -> bool {
+  !*value
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UrlPattern {
   pattern: String,
   name: String,
+  #[serde(default, skip_serializing_if = "RuleAction::is_exclude")]
+  action: RuleAction,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  factor: Option<f64>, // strength of a boost or demote rule, 2 if omitted
+  #[serde(default, skip_serializing_if = "RuleTarget::is_uri")]
+  target: RuleTarget,
+  #[serde(default, skip_serializing_if = "is_false")]
+  case_sensitive: bool, // patterns ignore case unless set
+}
+
+impl UrlPattern {
+  /// Patterns are addressed by name or by its slug, e.g. "Social Media" or social-media
+  fn matches_key(&self, key: &str) -> bool {
+    self.name.eq_ignore_ascii_case(key) || slugify(&self.name) == key
+  }
+}
+
+/// Profile used when a request or client key names none, holding the patterns file list
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Built-in profile without any filtering, unless the profiles file defines it
+const NO_FILTER_PROFILE: &str = "none";
+
+/// Named exclusion list from the PATH_TO_EXCLUSION_PROFILES file, e.g.
+/// `{"family": {"inherits": "default", "patterns": [...]}, "social": {"patterns": [...]}}`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExclusionProfile {
+  pub inherits: Option<String>, // profile whose patterns also apply
+  #[serde(default)]
+  pub patterns: Vec<UrlPattern>,
+}
+
+/// Change to the default pattern list made through the API
+pub enum PatternEdit {
+  Add(UrlPattern),
+  Update(String, UrlPattern),
+  Remove(String),
 }
 
-pub fn get_exclusion_patterns() -> Vec<UrlPattern> {
-  let fpath = dotenv::var("PATH_TO_EXCLUDE_PATTERNS").unwrap_or("exclusion_patterns.json".to_owned());
-  let mut rows: Vec<UrlPattern> = vec![];
-  if let Ok(contents) =  fs::read_to_string(fpath) {    
-    if let Ok(data) = serde_json::from_str::<Vec<UrlPattern>>(&contents) {
-      rows = data;
+/// Entry of the patterns or profiles file that cannot be used
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize)]
+pub struct PatternError {
+  pub profile: Option<String>, // set for entries of the profiles file
+  pub index: usize, // position in the file or profile
+  pub name: String,
+  pub pattern: String,
+  pub error: String,
+}
+
+/// Outcome of the latest attempt to load the patterns file
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExclusionStatus {
+  pub loaded: Option<i64>, // timestamp of the last good load
+  pub count: usize,
+  pub error: Option<String>, // error of the last load, if it failed
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub invalid: Vec<PatternError>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub profiles: Vec<String>, // profiles other than default
+}
+
+/// With EXCLUSIONS_STRICT=1 a single invalid pattern rejects the whole file,
+/// and the service refuses to start without a valid list
+pub fn strict_exclusions() -> bool {
+  dotenv::var("EXCLUSIONS_STRICT").unwrap_or_default() == "1"
+}
+
+fn check_pattern(row: &UrlPattern) -> Result<(), String> {
+  if row.pattern.trim().is_empty() {
+    // an empty pattern would match every result
+    return Err("empty pattern".to_owned());
+  }
+  if row.factor.is_some_and(|factor| !(factor.is_finite() && factor > 0.0)) {
+    return Err("factor must be greater than 0".to_owned());
+  }
+  RegexBuilder::new(&row.pattern).case_insensitive(!row.case_sensitive).build().map(|_| ()).map_err(|error| error.to_string())
+}
+
+/// Effect of all matching rules on one result
+pub enum RuleVerdict<'a> {
+  Exclude(&'a str), // name of the rule removing the result
+  Keep(f64, Vec<&'a str>), // weight multiplier and names of the allow, boost and demote rules that matched
+}
+
+/// Patterns sharing a target and case option, compiled into one RegexSet
+struct TargetSet {
+  target: RuleTarget,
+  set: RegexSet,
+  rules: Vec<usize>, // rule index of each pattern in the set
+}
+
+/// Rules compiled into one RegexSet per target and case option, so each field is scanned once
+pub struct ExclusionMatcher {
+  sets: Vec<TargetSet>,
+  names: Vec<String>,
+  actions: Vec<(RuleAction, Option<f64>)>,
+  has_allow: bool,
+}
+
+impl ExclusionMatcher {
+  /// Patterns that do not compile are skipped. Building fails if a combined set
+  /// cannot be compiled, e.g. when it exceeds the regex size limit.
+  pub fn new(rows: &[UrlPattern]) -> Result<Self, String> {
+    let valid_rows = rows.iter()
+      .filter(|row| check_pattern(row).is_ok())
+      .collect::<Vec<&UrlPattern>>();
+    let mut groups: Vec<((RuleTarget, bool), Vec<usize>)> = Vec::new();
+    for (index, row) in valid_rows.iter().enumerate() {
+      let group_key = (row.target, row.case_sensitive);
+      match groups.iter_mut().find(|(key, _)| *key == group_key) {
+        Some((_, rules)) => rules.push(index),
+        None => groups.push((group_key, vec![index])),
+      }
+    }
+    let mut sets = Vec::with_capacity(groups.len());
+    for ((target, case_sensitive), rules) in groups {
+      let set = RegexSetBuilder::new(rules.iter().map(|index| valid_rows[*index].pattern.as_str()))
+        .case_insensitive(!case_sensitive)
+        .build()
+        .map_err(|error| format!("cannot compile {} patterns for {:?}: {}", rules.len(), target, error))?;
+      sets.push(TargetSet { target, set, rules });
+    }
+    Ok(ExclusionMatcher {
+      sets,
+      names: valid_rows.iter().map(|row| row.name.clone()).collect(),
+      actions: valid_rows.iter().map(|row| (row.action, row.factor)).collect(),
+      has_allow: valid_rows.iter().any(|row| row.action == RuleAction::Allow),
+    })
+  }
+
+  /// Matcher without rules, used when a list cannot be compiled and no previous matcher exists
+  pub fn empty() -> Self {
+    ExclusionMatcher { sets: vec![], names: vec![], actions: vec![], has_allow: false }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.names.is_empty()
+  }
+
+  /// Indices of the rules matching a result, in list order
+  fn matching_rules(&self, row: &SearchResult) -> Vec<usize> {
+    let fields = ResultFields::new(row);
+    let mut matches = Vec::new();
+    for target_set in self.sets.iter() {
+      for text in target_set.target.texts(&fields) {
+        matches.extend(target_set.set.matches(text).into_iter().map(|index| target_set.rules[index]));
+      }
+    }
+    matches.sort();
+    matches.dedup();
+    matches
+  }
+
+  /// Exclude rules take precedence over allow, boost and demote rules
+  pub fn evaluate(&self, row: &SearchResult) -> RuleVerdict<'_> {
+    let matches = self.matching_rules(row);
+    if let Some(index) = matches.iter().find(|index| self.actions[**index].0 == RuleAction::Exclude) {
+      return RuleVerdict::Exclude(&self.names[*index]);
+    }
+    if self.has_allow && !matches.iter().any(|index| self.actions[*index].0 == RuleAction::Allow) {
+      return RuleVerdict::Exclude(NOT_ALLOWED);
+    }
+    let multiplier = matches.iter().map(|index| {
+      let (action, factor) = self.actions[*index];
+      action.weight_multiplier(factor)
+    }).product();
+    RuleVerdict::Keep(multiplier, matches.iter().map(|index| self.names[*index].as_str()).collect())
+  }
+}
+
+/// Resolved pattern list of a profile with its compiled matcher
+struct CompiledProfile {
+  patterns: Arc<Vec<UrlPattern>>,
+  matcher: Arc<ExclusionMatcher>,
+}
+
+#[derive(Default)]
+struct LoadedPatterns {
+  profiles: HashMap<String, CompiledProfile>,
+  status: ExclusionStatus,
+}
+
+/// Last good profiles read from the files, swapped as a whole on reload
+fn loaded_patterns() -> &'static RwLock<LoadedPatterns> {
+  static LOADED: OnceLock<RwLock<LoadedPatterns>> = OnceLock::new();
+  LOADED.get_or_init(|| RwLock::new(LoadedPatterns::default()))
+}
+
+fn exclusion_patterns_path() -> String {
+  dotenv::var("PATH_TO_EXCLUDE_PATTERNS").unwrap_or("exclusion_patterns.json".to_owned())
+}
+
+fn exclusion_profiles_path() -> Option<String> {
+  dotenv::var("PATH_TO_EXCLUSION_PROFILES").ok().filter(|fpath| !fpath.trim().is_empty())
+}
+
+/// Split rows into valid entries and those that do not compile
+fn check_patterns(rows: Vec<UrlPattern>, profile: Option<&str>) -> (Vec<UrlPattern>, Vec<PatternError>) {
+  let mut valid = Vec::with_capacity(rows.len());
+  let mut invalid = Vec::new();
+  for (index, row) in rows.into_iter().enumerate() {
+    match check_pattern(&row) {
+      Ok(()) => valid.push(row),
+      Err(error) => invalid.push(PatternError { profile: profile.map(|p| p.to_owned()), index, name: row.name, pattern: row.pattern, error }),
+    }
+  }
+  (valid, invalid)
+}
+
+/// Read the patterns file, separating valid entries from those that do not compile.
+/// An unreadable or unparseable file is an error.
+fn read_exclusion_patterns(fpath: &str) -> Result<(Vec<UrlPattern>, Vec<PatternError>), String> {
+  let contents = fs::read_to_string(fpath).map_err(|error| format!("cannot read {}: {}", fpath, error))?;
+  let rows = serde_json::from_str::<Vec<UrlPattern>>(&contents).map_err(|error| format!("cannot parse {}: {}", fpath, error))?;
+  Ok(check_patterns(rows, None))
+}
+
+/// Profile names are matched case-insensitively, so they are lowercased as requests are.
+/// The profiles file is optional, so a missing file means no profiles.
+fn read_exclusion_profiles(fpath: &str) -> Result<HashMap<String, ExclusionProfile>, String> {
+  let contents = match fs::read_to_string(fpath) {
+    Ok(contents) => contents,
+    Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+    Err(error) => return Err(format!("cannot read {}: {}", fpath, error)),
+  };
+  let rows = serde_json::from_str::<HashMap<String, ExclusionProfile>>(&contents).map_err(|error| format!("cannot parse {}: {}", fpath, error))?;
+  let mut profiles = HashMap::with_capacity(rows.len());
+  for (name, mut profile) in rows {
+    profile.inherits = profile.inherits.map(|parent| parent.trim().to_lowercase());
+    if profiles.insert(name.trim().to_lowercase(), profile).is_some() {
+      return Err(format!("duplicate profile {} in {}", name.trim().to_lowercase(), fpath));
+    }
+  }
+  Ok(profiles)
+}
+
+/// Patterns of a profile after its inheritance chain, parents first
+fn resolve_profile(name: &str, base: &[UrlPattern], profiles: &HashMap<String, ExclusionProfile>, seen: &mut Vec<String>) -> Result<Vec<UrlPattern>, String> {
+  if name == DEFAULT_PROFILE {
+    return Ok(base.to_vec());
+  }
+  if seen.iter().any(|other| other == name) {
+    return Err(format!("inheritance loop at profile {}", name));
+  }
+  let profile = profiles.get(name).ok_or(format!("unknown profile {}", name))?;
+  seen.push(name.to_owned());
+  let mut rows = match profile.inherits.as_deref() {
+    Some(parent) => resolve_profile(parent, base, profiles, seen)?,
+    None => vec![]
+  };
+  for row in profile.patterns.iter() {
+    if !rows.iter().any(|other| other.pattern == row.pattern) {
+      rows.push(row.clone());
     }
   }
-  rows
+  Ok(rows)
 }
 
-pub fn get_exclusion_pattern_strings() -> Vec<String> {
-  let cached_rows = redis_get_exclusions();
-  let items = if cached_rows.len() > 0 {
+/// Resolved pattern lists keyed by profile name
+type ProfileLists = HashMap<String, Vec<UrlPattern>>;
+
+/// Read the patterns file and the optional profiles file into resolved lists per profile
+fn read_all_profiles(fpath: &str) -> Result<(ProfileLists, Vec<PatternError>), String> {
+  let (base, mut invalid) = read_exclusion_patterns(fpath)?;
+  let mut profiles = match exclusion_profiles_path() {
+    Some(profiles_path) => read_exclusion_profiles(&profiles_path)?,
+    None => HashMap::new(),
+  };
+  profiles.remove(DEFAULT_PROFILE);
+  profiles.entry(NO_FILTER_PROFILE.to_owned()).or_default();
+  for (name, profile) in profiles.iter_mut() {
+    let (valid, profile_invalid) = check_patterns(std::mem::take(&mut profile.patterns), Some(name));
+    profile.patterns = valid;
+    invalid.extend(profile_invalid);
+  }
+  let mut resolved = HashMap::with_capacity(profiles.len() + 1);
+  for name in profiles.keys() {
+    resolved.insert(name.to_owned(), resolve_profile(name, &base, &profiles, &mut vec![])?);
+  }
+  resolved.insert(DEFAULT_PROFILE.to_owned(), base);
+  Ok((resolved, invalid))
+}
+
+fn current_exclusion_patterns(profile: &str) -> Option<Arc<Vec<UrlPattern>>> {
+  let loaded = loaded_patterns().read().unwrap_or_else(|e| e.into_inner());
+  loaded.profiles.get(profile).map(|compiled| compiled.patterns.clone())
+}
+
+pub fn exclusion_status() -> ExclusionStatus {
+  loaded_patterns().read().unwrap_or_else(|e| e.into_inner()).status.clone()
+}
+
+/// Patterns of a profile in memory, or read from the file if none have been loaded yet
+pub fn get_exclusion_patterns(profile: &str) -> Vec<UrlPattern> {
+  if let Some(rows) = current_exclusion_patterns(profile) {
+    return rows.to_vec();
+  }
+  if exclusion_status().loaded.is_none() && profile == DEFAULT_PROFILE {
+    read_exclusion_patterns(&exclusion_patterns_path()).map(|(rows, _)| rows).unwrap_or_default()
+  } else {
+    vec![]
+  }
+}
+
+/// Whether a profile exists, either loaded here or cached by another instance
+pub async fn has_exclusion_profile(store: &dyn CacheStore, profile: &str) -> bool {
+  if profile == DEFAULT_PROFILE || profile == NO_FILTER_PROFILE {
+    return true;
+  }
+  if exclusion_status().loaded.is_some() {
+    current_exclusion_patterns(profile).is_some()
+  } else {
+    !cache_get_exclusions(store, profile).await.is_empty()
+  }
+}
+
+/// Serialises reloads from the watcher, SIGHUP, the API and edits, so that a slower
+/// reload cannot swap in a file read before a newer one
+fn reload_lock() -> &'static tokio::sync::Mutex<()> {
+  static LOCK: OnceLock<tokio::sync::Mutex<()>> = OnceLock::new();
+  LOCK.get_or_init(|| tokio::sync::Mutex::new(()))
+}
+
+/// Reload the patterns and profiles files and replace the in-memory and cached lists.
+/// Invalid entries are skipped and reported, or reject the files in strict mode.
+/// On error the previous lists stay in use and the error is kept for /exclusions.
+pub async fn reload_exclusion_patterns(store: &dyn CacheStore) -> Result<usize, String> {
+  let _guard = reload_lock().lock().await;
+  let fpath = exclusion_patterns_path();
+  let loaded_rows = read_all_profiles(&fpath).and_then(|(profiles, invalid)| {
+    for entry in invalid.iter() {
+      tracing::error!("invalid exclusion pattern {} ({}) at index {}: {}", entry.name, entry.pattern, entry.index, entry.error);
+    }
+    if strict_exclusions() && !invalid.is_empty() {
+      loaded_patterns().write().unwrap_or_else(|e| e.into_inner()).status.invalid = invalid.clone();
+      Err(format!("{} invalid patterns in {}", invalid.len(), fpath))
+    } else {
+      Ok((profiles, invalid))
+    }
+  });
+  // a list that cannot be compiled as a whole is rejected like an unreadable file
+  let compiled_rows = loaded_rows.and_then(|(profiles, invalid)| {
+    let matchers = profiles.iter()
+      .map(|(name, rows)| ExclusionMatcher::new(rows).map(|matcher| (name.to_owned(), Arc::new(matcher))).map_err(|error| format!("profile {}: {}", name, error)))
+      .collect::<Result<HashMap<String, Arc<ExclusionMatcher>>, String>>()?;
+    Ok((profiles, matchers, invalid))
+  });
+  match compiled_rows {
+    Ok((profiles, mut matchers, invalid)) => {
+      let count = profiles.get(DEFAULT_PROFILE).map(|rows| rows.len()).unwrap_or(0);
+      for (name, rows) in profiles.iter() {
+        cache_set_exclusions(store, name, rows).await;
+      }
+      let mut names = profiles.keys().filter(|name| *name != DEFAULT_PROFILE).cloned().collect::<Vec<String>>();
+      names.sort();
+      let compiled = profiles.into_iter().filter_map(|(name, rows)| {
+        let matcher = matchers.remove(&name)?;
+        Some((name, CompiledProfile { patterns: Arc::new(rows), matcher }))
+      }).collect();
+      let mut loaded = loaded_patterns().write().unwrap_or_else(|e| e.into_inner());
+      loaded.profiles = compiled;
+      loaded.status = ExclusionStatus { loaded: Some(get_timestamp()), count, error: None, invalid, profiles: names };
+      tracing::info!("loaded {} exclusion patterns from {}", count, fpath);
+      Ok(count)
+    },
+    Err(error) => {
+      tracing::error!("keeping previous exclusion patterns: {}", error);
+      loaded_patterns().write().unwrap_or_else(|e| e.into_inner()).status.error = Some(error.clone());
+      Err(error)
+    }
+  }
+}
+
+/// Serialized rules with the matcher compiled from them
+type CompiledPatterns = (String, Arc<ExclusionMatcher>);
+
+/// Matchers compiled from lists cached by another instance, reused while a list is unchanged
+fn fallback_matchers() -> &'static Mutex<HashMap<String, CompiledPatterns>> {
+  static FALLBACK: OnceLock<Mutex<HashMap<String, CompiledPatterns>>> = OnceLock::new();
+  FALLBACK.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Compiled matcher for the patterns of a profile. Patterns are only compiled when they are loaded.
+pub async fn get_exclusion_matcher(store: &dyn CacheStore, profile: &str) -> Arc<ExclusionMatcher> {
+  if let Some(compiled) = loaded_patterns().read().unwrap_or_else(|e| e.into_inner()).profiles.get(profile) {
+    return compiled.matcher.clone();
+  }
+  // without local files, use the lists cached by another instance
+  let cached_rows = cache_get_exclusions(store, profile).await;
+  let rows = if !cached_rows.is_empty() {
     cached_rows
   } else {
-    let rows = get_exclusion_patterns();
-    if rows.len() > 0 {
-      redis_set_exclusions(&rows);
+    let rows = get_exclusion_patterns(profile);
+    if !rows.is_empty() {
+      cache_set_exclusions(store, profile, &rows).await;
     }
     rows
   };
-  items.into_iter().map(|row| row.pattern).collect::<Vec<String>>()
-}
\ No newline at end of file
+  let rules_json = serde_json::to_string(&rows).unwrap_or_default();
+  let mut fallback = fallback_matchers().lock().unwrap_or_else(|e| e.into_inner());
+  match fallback.get(profile) {
+    Some((compiled_json, matcher)) if *compiled_json == rules_json => matcher.clone(),
+    previous => match ExclusionMatcher::new(&rows) {
+      Ok(matcher) => {
+        let matcher = Arc::new(matcher);
+        fallback.insert(profile.to_owned(), (rules_json, matcher.clone()));
+        matcher
+      },
+      // keep the previous matcher rather than silently dropping every rule
+      Err(error) => {
+        tracing::error!("cannot compile cached exclusion patterns for profile {}: {}", profile, error);
+        loaded_patterns().write().unwrap_or_else(|e| e.into_inner()).status.error = Some(format!("profile {}: {}", profile, error));
+        previous.map(|(_, matcher)| matcher.clone()).unwrap_or_else(|| Arc::new(ExclusionMatcher::empty()))
+      }
+    }
+  }
+}
+
+/// Serialises edits so that concurrent changes are not lost
+fn edit_lock() -> &'static tokio::sync::Mutex<()> {
+  static LOCK: OnceLock<tokio::sync::Mutex<()>> = OnceLock::new();
+  LOCK.get_or_init(|| tokio::sync::Mutex::new(()))
+}
+
+/// Write the list to a temporary file first so that the watcher never reads a partial file
+fn write_exclusion_patterns(fpath: &str, rows: &[UrlPattern]) -> Result<(), String> {
+  let contents = serde_json::to_string_pretty(rows).map_err(|error| error.to_string())?;
+  let tmp_path = format!("{}.tmp", fpath);
+  fs::write(&tmp_path, contents).map_err(|error| format!("cannot write {}: {}", tmp_path, error))?;
+  fs::rename(&tmp_path, fpath).map_err(|error| format!("cannot replace {}: {}", fpath, error))
+}
+
+/// Apply an edit and persist it to the patterns file, or to the cache store when
+/// there is no patterns file. The new list takes effect immediately.
+pub async fn edit_exclusion_patterns(store: &dyn CacheStore, edit: PatternEdit) -> Result<Vec<UrlPattern>, AppError> {
+  if let PatternEdit::Add(row) | PatternEdit::Update(_, row) = &edit {
+    if row.name.trim().is_empty() {
+      return Err(AppError::InvalidPattern("a name is required".to_owned()));
+    }
+    check_pattern(row).map_err(AppError::InvalidPattern)?;
+  }
+  let _guard = edit_lock().lock().await;
+  let fpath = exclusion_patterns_path();
+  let from_file = Path::new(&fpath).exists();
+  // edit the file as it is, so that entries skipped as invalid are kept for fixing
+  let mut rows = if from_file {
+    let contents = fs::read_to_string(&fpath).map_err(|error| AppError::PatternsNotSaved(error.to_string()))?;
+    serde_json::from_str::<Vec<UrlPattern>>(&contents).map_err(|error| AppError::PatternsNotSaved(error.to_string()))?
+  } else {
+    cache_get_exclusions(store, DEFAULT_PROFILE).await
+  };
+  match edit {
+    PatternEdit::Add(row) => {
+      if rows.iter().any(|other| other.matches_key(&row.name)) {
+        return Err(AppError::PatternExists(row.name));
+      }
+      rows.push(row);
+    },
+    PatternEdit::Update(key, row) => {
+      let index = rows.iter().position(|other| other.matches_key(&key)).ok_or(AppError::PatternNotFound(key))?;
+      if rows.iter().enumerate().any(|(other_index, other)| other_index != index && other.matches_key(&row.name)) {
+        return Err(AppError::PatternExists(row.name));
+      }
+      rows[index] = row;
+    },
+    PatternEdit::Remove(key) => {
+      let index = rows.iter().position(|other| other.matches_key(&key)).ok_or(AppError::PatternNotFound(key))?;
+      rows.remove(index);
+    },
+  }
+  if from_file {
+    write_exclusion_patterns(&fpath, &rows).map_err(AppError::PatternsNotSaved)?;
+    // the edit is kept in the file even if the list as a whole no longer loads
+    reload_exclusion_patterns(store).await.map_err(AppError::PatternsNotLoaded)?;
+  } else if cache_set_exclusions(store, DEFAULT_PROFILE, &rows).await.is_none() {
+    return Err(AppError::CacheUnavailable(format!("cannot save exclusion patterns in {} store", store.name())));
+  }
+  Ok(rows)
+}
+
+fn modified_time(fpath: &str) -> Option<SystemTime> {
+  fs::metadata(fpath).and_then(|meta| meta.modified()).ok()
+}
+
+/// Modification times of the patterns and profiles files
+fn files_modified() -> (Option<SystemTime>, Option<SystemTime>) {
+  (modified_time(&exclusion_patterns_path()), exclusion_profiles_path().and_then(|fpath| modified_time(&fpath)))
+}
+
+/// Reload the patterns and profiles files whenever their modification times change
+/// (polled every EXCLUSIONS_POLL_SECS, default 10, 0 to disable) or the process receives SIGHUP
+pub fn spawn_exclusion_watcher(store: Arc<dyn CacheStore>) {
+  let poll_secs = dotenv::var("EXCLUSIONS_POLL_SECS").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(10);
+  let poll_store = store.clone();
+  let mut last_modified = files_modified();
+  tokio::spawn(async move {
+    if poll_secs < 1 {
+      return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(poll_secs));
+    loop {
+      interval.tick().await;
+      let modified = files_modified();
+      if modified.0.is_some() && modified != last_modified {
+        last_modified = modified;
+        let _ = reload_exclusion_patterns(poll_store.as_ref()).await;
+      }
+    }
+  });
+  #[cfg(unix)]
+  tokio::spawn(async move {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+      Ok(hangup) => hangup,
+      Err(error) => {
+        tracing::error!("cannot listen for SIGHUP: {}", error);
+        return;
+      }
+    };
+    while hangup.recv().await.is_some() {
+      tracing::info!("SIGHUP received, reloading exclusion patterns");
+      let _ = reload_exclusion_patterns(store.as_ref()).await;
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+  use super::*;
+
+  fn rules(rows: Value) -> Vec<UrlPattern> {
+    serde_json::from_value(rows).unwrap()
+  }
+
+  fn result(uri: &str, title: &str, summary: &str) -> SearchResult {
+    SearchResult::new(&json!({ "url": uri, "title": title, "description": summary }), 0)
+  }
+
+  fn excluded_by(matcher: &ExclusionMatcher, row: &SearchResult) -> Option<String> {
+    match matcher.evaluate(row) {
+      RuleVerdict::Exclude(name) => Some(name.to_owned()),
+      RuleVerdict::Keep(..) => None,
+    }
+  }
+
+  #[test]
+  fn exclude_rules_win_over_allow_rules() {
+    let matcher = ExclusionMatcher::new(&rules(json!([
+      { "name": "Docs", "pattern": "^https://docs\\.", "action": "allow" },
+      { "name": "Drafts", "pattern": "/drafts/" },
+    ]))).unwrap();
+    assert_eq!(excluded_by(&matcher, &result("https://docs.rs/drafts/a", "", "")), Some("Drafts".to_owned()));
+    assert_eq!(excluded_by(&matcher, &result("https://docs.rs/serde", "", "")), None);
+  }
+
+  #[test]
+  fn allow_rules_exclude_everything_else() {
+    let matcher = ExclusionMatcher::new(&rules(json!([
+      { "name": "Docs", "pattern": "^https://docs\\.", "action": "allow" },
+      { "name": "Rust", "pattern": "rust", "action": "boost" },
+    ]))).unwrap();
+    assert_eq!(excluded_by(&matcher, &result("https://rust-lang.org", "", "")), Some(NOT_ALLOWED.to_owned()));
+    match matcher.evaluate(&result("https://docs.rs/rust", "", "")) {
+      RuleVerdict::Keep(multiplier, names) => {
+        assert_eq!(multiplier, 0.5);
+        assert_eq!(names, vec!["Docs", "Rust"]);
+      },
+      RuleVerdict::Exclude(name) => panic!("excluded by {}", name),
+    }
+  }
+
+  #[test]
+  fn boost_and_demote_factors_multiply() {
+    let matcher = ExclusionMatcher::new(&rules(json!([
+      { "name": "Boost", "pattern": "a\\.com", "action": "boost", "factor": 4 },
+      { "name": "Demote", "pattern": "/old/", "action": "demote" },
+    ]))).unwrap();
+    assert!(matches!(matcher.evaluate(&result("https://a.com/old/x", "", "")), RuleVerdict::Keep(multiplier, _) if multiplier == 0.5));
+    assert!(matches!(matcher.evaluate(&result("https://b.com/x", "", "")), RuleVerdict::Keep(multiplier, names) if multiplier == 1.0 && names.is_empty()));
+  }
+
+  #[test]
+  fn rules_match_their_target_field_only() {
+    let matcher = ExclusionMatcher::new(&rules(json!([
+      { "name": "Title", "pattern": "sponsored", "target": "title" },
+      { "name": "Host", "pattern": "^ads\\.", "target": "host" },
+      { "name": "Case", "pattern": "CASINO", "target": "summary", "case_sensitive": true },
+    ]))).unwrap();
+    assert_eq!(excluded_by(&matcher, &result("https://a.com/sponsored", "News", "")), None);
+    assert_eq!(excluded_by(&matcher, &result("https://a.com", "<b>Sponsored</b> post", "")), Some("Title".to_owned()));
+    assert_eq!(excluded_by(&matcher, &result("https://ads.a.com/x", "", "")), Some("Host".to_owned()));
+    assert_eq!(excluded_by(&matcher, &result("https://a.com/ads.html", "", "")), None);
+    assert_eq!(excluded_by(&matcher, &result("https://a.com", "", "casino")), None);
+    assert_eq!(excluded_by(&matcher, &result("https://a.com", "", "CASINO")), Some("Case".to_owned()));
+  }
+
+  #[test]
+  fn invalid_rows_are_skipped() {
+    let matcher = ExclusionMatcher::new(&rules(json!([
+      { "name": "Broken", "pattern": "(" },
+      { "name": "Empty", "pattern": " " },
+      { "name": "Facebook", "pattern": "facebook\\.com" },
+    ]))).unwrap();
+    assert_eq!(matcher.names, vec!["Facebook"]);
+    assert!(ExclusionMatcher::new(&[]).unwrap().is_empty());
+  }
+
+  #[test]
+  fn sets_over_the_size_limit_are_an_error() {
+    // each pattern compiles alone, but together they exceed the regex size limit
+    let rows = rules(json!([
+      { "name": "Long", "pattern": "(?:a{1000}){200}", "case_sensitive": true },
+      { "name": "Longer", "pattern": "(?:a{1000}){200}b", "case_sensitive": true },
+    ]));
+    assert!(rows.iter().all(|row| check_pattern(row).is_ok()));
+    assert!(ExclusionMatcher::new(&rows).is_err());
+  }
+
+  fn profiles(rows: Value) -> HashMap<String, ExclusionProfile> {
+    serde_json::from_value(rows).unwrap()
+  }
+
+  fn pattern_names(rows: &[UrlPattern]) -> Vec<&str> {
+    rows.iter().map(|row| row.name.as_str()).collect()
+  }
+
+  #[test]
+  fn profiles_inherit_parent_patterns_first() {
+    let base = rules(json!([{ "name": "Base", "pattern": "base" }]));
+    let profiles = profiles(json!({
+      "family": { "inherits": "default", "patterns": [{ "name": "Family", "pattern": "family" }] },
+      "kids": { "inherits": "family", "patterns": [{ "name": "Kids", "pattern": "kids" }, { "name": "Again", "pattern": "base" }] },
+      "social": { "patterns": [{ "name": "Social", "pattern": "social" }] },
+    }));
+    let kids = resolve_profile("kids", &base, &profiles, &mut vec![]).unwrap();
+    assert_eq!(pattern_names(&kids), vec!["Base", "Family", "Kids"]);
+    let social = resolve_profile("social", &base, &profiles, &mut vec![]).unwrap();
+    assert_eq!(pattern_names(&social), vec!["Social"]);
+  }
+
+  #[test]
+  fn inheritance_loops_are_an_error() {
+    let profiles = profiles(json!({
+      "a": { "inherits": "b" },
+      "b": { "inherits": "c" },
+      "c": { "inherits": "a" },
+      "self": { "inherits": "self" },
+      "orphan": { "inherits": "missing" },
+    }));
+    assert_eq!(resolve_profile("a", &[], &profiles, &mut vec![]).unwrap_err(), "inheritance loop at profile a");
+    assert_eq!(resolve_profile("self", &[], &profiles, &mut vec![]).unwrap_err(), "inheritance loop at profile self");
+    assert_eq!(resolve_profile("orphan", &[], &profiles, &mut vec![]).unwrap_err(), "unknown profile missing");
+  }
+
+  #[test]
+  fn profile_names_are_lowercased_on_load() {
+    let fpath = std::env::temp_dir().join(format!("exclusion_profiles_{}.json", std::process::id()));
+    fs::write(&fpath, r#"{"Family": {"inherits": "Default"}, "Kids": {"inherits": " FAMILY "}}"#).unwrap();
+    let loaded = read_exclusion_profiles(fpath.to_str().unwrap());
+    fs::write(&fpath, r#"{"Family": {}, "family": {}}"#).unwrap();
+    let duplicate = read_exclusion_profiles(fpath.to_str().unwrap());
+    let _ = fs::remove_file(&fpath);
+    let loaded = loaded.unwrap();
+    assert_eq!(loaded["family"].inherits.as_deref(), Some("default"));
+    assert_eq!(loaded["kids"].inherits.as_deref(), Some("family"));
+    assert!(resolve_profile("kids", &[], &loaded, &mut vec![]).is_ok());
+    assert!(duplicate.unwrap_err().starts_with("duplicate profile family"));
+  }
+
+  #[test]
+  fn a_missing_profiles_file_means_no_profiles() {
+    let fpath = std::env::temp_dir().join(format!("missing_exclusion_profiles_{}.json", std::process::id()));
+    assert!(read_exclusion_profiles(fpath.to_str().unwrap()).unwrap().is_empty());
+  }
+}
@@ -10,13 +10,23 @@ mod cache;
 mod utils;
 mod options;
 mod exclusions;
+mod backends;
+mod errors;
+mod merge;
+mod similarity;
+mod state;
+mod stores;
+mod quota;
+mod rate_limit;
+mod clients;
 
 use axum::Router;
 use std::net::SocketAddr;
 use std::time::Duration;
 use axum::{
     http::{header, HeaderValue},
-    routing::{get, post},
+    middleware,
+    routing::{get, put},
 };
 use tower_http::{
     limit::RequestBodyLimitLayer,
@@ -27,35 +37,45 @@ use tower_http::{
 };
 
 use routes::*;
+use state::AppState;
+use rate_limit::rate_limit;
+use clients::authenticate;
 
 fn get_max_timeout_secs() -> u64 {
      // timeout requests after 5 minutes, returning 408 status code
     let max_timeout_val = if let Ok(mt_val) = dotenv::var("MAX_TIMEOUT") { mt_val } else { "300".to_owned() };
-    if let Ok(to_secs) = u64::from_str_radix(&max_timeout_val, 10) {
-        to_secs
-    } else {
-        300
-    }
+    max_timeout_val.parse::<u64>().unwrap_or(300)
 }
 
 fn get_port_number() -> u16 {
     let env_port = if let Ok(port_ref) = dotenv::var("PORT") { port_ref } else { "3000".to_owned() };
-    if let Ok(p) = u16::from_str_radix(&env_port, 10) {
-        p
-    } else { 
-        3000
-    }
+    env_port.parse::<u16>().unwrap_or(3000)
 }
 
 #[tokio::main]
 async fn main() {
     let max_timeout_secs = get_max_timeout_secs();
+    let state = AppState::from_env();
+    if let Err(error) = exclusions::reload_exclusion_patterns(state.cache.as_ref()).await {
+        if exclusions::strict_exclusions() {
+            eprintln!("refusing to start with EXCLUSIONS_STRICT=1: {}", error);
+            std::process::exit(1);
+        }
+    }
+    exclusions::spawn_exclusion_watcher(state.cache.clone());
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/search", get(search_data_response))
         .route("/suggest", get(suggest_data_response))
 
-        .route("/exclusions", get(list_exclusion_patterns))
+        .route("/exclusions", get(list_exclusion_patterns).post(add_exclusion_pattern))
+        .route("/exclusions/:key", put(update_exclusion_pattern).delete(delete_exclusion_pattern))
+        .route("/admin/usage", get(admin_usage))
+        // throttle /search and /suggest per client key or IP, returning 429 status code
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+        // resolve client keys first, returning 401 status code for unknown keys
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .with_state(state)
         .layer(CorsLayer::permissive())
         .layer(TimeoutLayer::new(Duration::from_secs(max_timeout_secs)))
         // don't allow request bodies larger than 1024 bytes, returning 413 status code
@@ -71,7 +91,7 @@ async fn main() {
     tracing::debug!("listening on {}", addr);
     println!("listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
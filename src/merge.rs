@@ -0,0 +1,150 @@
+use std::cmp::Ordering;
+
+use crate::{models::SearchResult, options::{MergeStrategy, SearchProvider}};
+
+/// Constant used by reciprocal rank fusion to dampen the advantage of top ranks
+const RRF_K: f64 = 60.0;
+
+/// Per-provider rank factor, e.g. BRAVE_RANK_FACTOR=7.
+/// A greater factor gives the provider's results less influence on the merged ranking.
+pub fn provider_rank_factor(provider: SearchProvider) -> usize {
+  let (env_key, default_factor) = match provider {
+    SearchProvider::Brave => ("BRAVE_RANK_FACTOR", 7),
+    SearchProvider::Mojeek => ("MOJEEK_RANK_FACTOR", 4),
+    SearchProvider::Text => ("TEXTSURF_RANK_FACTOR", 5),
+  };
+  match dotenv::var(env_key).ok().and_then(|v| v.parse::<usize>().ok()) {
+    Some(factor) if factor > 0 => factor,
+    _ => default_factor
+  }
+}
+
+/// Initial weight of a result before merging: greater weight, lower ranking
+pub fn rank_weight(provider: SearchProvider, rank: usize) -> usize {
+  rank * provider_rank_factor(provider)
+}
+
+fn provider_importance(provider: SearchProvider) -> f64 {
+  1.0 / provider_rank_factor(provider) as f64
+}
+
+/// Merge groups of results sharing the same dedupe key into one ranked list.
+/// The first row of each group is kept for display. Providers are listed in
+/// priority order, which settles ties and sets the interleave sequence.
+pub fn merge_groups(groups: Vec<Vec<SearchResult>>, strategy: MergeStrategy, providers: &[SearchProvider]) -> Vec<SearchResult> {
+  match strategy {
+    MergeStrategy::Legacy => merge_legacy(groups),
+    MergeStrategy::Rrf => merge_by_score(groups, |row| provider_importance(row.provider) / (RRF_K + row.rank as f64 + 1.0)),
+    MergeStrategy::Borda => {
+      let depth = groups.iter().flatten().map(|row| row.rank + 1).max().unwrap_or(0);
+      merge_by_score(groups, |row| provider_importance(row.provider) * (depth - row.rank) as f64)
+    },
+    MergeStrategy::Interleave => merge_interleave(groups, providers),
+  }
+}
+
+/// Weights are rank times provider factor, reduced by the weight of each duplicate
+fn merge_legacy(groups: Vec<Vec<SearchResult>>) -> Vec<SearchResult> {
+  let mut rows: Vec<SearchResult> = groups.into_iter().filter_map(|group| {
+    let mut members = group.into_iter();
+    members.next().map(|mut primary| {
+      for other in members {
+        primary.subtract_weight(other.weight);
+      }
+      primary
+    })
+  }).collect();
+  rows.sort_by_key(|row| row.weight);
+  rows
+}
+
+fn merge_by_score<F>(groups: Vec<Vec<SearchResult>>, score_fn: F) -> Vec<SearchResult> where F: Fn(&SearchResult) -> f64 {
+  let mut scored: Vec<(f64, SearchResult)> = groups.into_iter().filter_map(|group| {
+    let score = group.iter().map(&score_fn).sum::<f64>();
+    group.into_iter().next().map(|primary| (score, primary))
+  }).collect();
+  scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+  with_position_weights(scored.into_iter().map(|(_, row)| row).collect())
+}
+
+/// Take the best remaining result from each provider in turn
+fn merge_interleave(groups: Vec<Vec<SearchResult>>, providers: &[SearchProvider]) -> Vec<SearchResult> {
+  let provider_index = |provider: SearchProvider| providers.iter().position(|p| *p == provider).unwrap_or(providers.len());
+  let mut keyed: Vec<((usize, usize), SearchResult)> = groups.into_iter().filter_map(|group| {
+    let key = group.iter().map(|row| (row.rank, provider_index(row.provider))).min();
+    match (key, group.into_iter().next()) {
+      (Some(key), Some(primary)) => Some((key, primary)),
+      _ => None
+    }
+  }).collect();
+  keyed.sort_by_key(|(key, _)| *key);
+  with_position_weights(keyed.into_iter().map(|(_, row)| row).collect())
+}
+
+fn with_position_weights(mut rows: Vec<SearchResult>) -> Vec<SearchResult> {
+  for (position, row) in rows.iter_mut().enumerate() {
+    row.weight = position;
+  }
+  rows
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use super::*;
+
+  fn brave(uri: &str, rank: usize) -> SearchResult {
+    SearchResult::new(&json!({ "url": uri }), rank)
+  }
+
+  fn mojeek(uri: &str, rank: usize) -> SearchResult {
+    SearchResult::new_from_mojeek(&json!({ "url": uri }), rank)
+  }
+
+  /// a is found by both providers, b only by Mojeek and c only by Brave
+  fn groups() -> Vec<Vec<SearchResult>> {
+    vec![
+      vec![brave("https://a.com", 0), mojeek("https://a.com", 2)],
+      vec![mojeek("https://b.com", 0)],
+      vec![brave("https://c.com", 1)],
+    ]
+  }
+
+  fn merged_uris(strategy: MergeStrategy, providers: &[SearchProvider]) -> Vec<String> {
+    merge_groups(groups(), strategy, providers).into_iter().map(|row| row.uri).collect()
+  }
+
+  #[test]
+  fn keeps_the_first_row_of_each_group() {
+    let rows = merge_groups(groups(), MergeStrategy::Rrf, &[SearchProvider::Brave, SearchProvider::Mojeek]);
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0].uri, "https://a.com");
+    assert_eq!(rows[0].provider, SearchProvider::Brave);
+    assert_eq!(rows.iter().map(|row| row.weight).collect::<Vec<usize>>(), vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn duplicates_lift_results_in_legacy_merge() {
+    let rows = merge_groups(groups(), MergeStrategy::Legacy, &[]);
+    assert_eq!(rows.iter().map(|row| row.uri.as_str()).collect::<Vec<&str>>(), vec!["https://a.com", "https://b.com", "https://c.com"]);
+    assert_eq!(rows[0].weight, 0);
+    assert_eq!(rows[2].weight, rank_weight(SearchProvider::Brave, 1));
+  }
+
+  #[test]
+  fn borda_favours_top_ranks_over_agreement() {
+    assert_eq!(merged_uris(MergeStrategy::Borda, &[]), vec!["https://b.com", "https://a.com", "https://c.com"]);
+  }
+
+  #[test]
+  fn interleave_follows_provider_priority() {
+    assert_eq!(merged_uris(MergeStrategy::Interleave, &[SearchProvider::Brave, SearchProvider::Mojeek]), vec!["https://a.com", "https://b.com", "https://c.com"]);
+    assert_eq!(merged_uris(MergeStrategy::Interleave, &[SearchProvider::Mojeek, SearchProvider::Brave]), vec!["https://b.com", "https://a.com", "https://c.com"]);
+  }
+
+  #[test]
+  fn empty_groups_are_skipped() {
+    let rows = merge_groups(vec![vec![], vec![brave("https://a.com", 0)]], MergeStrategy::Interleave, &[SearchProvider::Brave]);
+    assert_eq!(rows.len(), 1);
+  }
+}
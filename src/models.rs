@@ -1,15 +1,20 @@
+use std::collections::HashSet;
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
 use serde_with::skip_serializing_none;
-use crate::{cache::get_timestamp, options::{BraveSearchOptions, SearchProvider}, utils::{find_position_in_strings, uri_is_excluded}, exclusions::get_exclusion_pattern_strings};
+use crate::{cache::get_timestamp, options::{BraveSearchOptions, SearchProvider, MergeStrategy, FoldMode}, similarity::{shingles, jaccard}, merge::{merge_groups, rank_weight}, utils::{find_position_in_strings, canonical_url}, exclusions::{ExclusionMatcher, RuleVerdict}};
+
+/// Minimum title and summary similarity, between 0 and 1, for two results to be folded together
+pub fn near_duplicate_threshold() -> f64 {
+  match dotenv::var("NEAR_DUPLICATE_THRESHOLD").ok().and_then(|v| v.parse::<f64>().ok()) {
+    Some(threshold) if threshold > 0.0 && threshold <= 1.0 => threshold,
+    _ => 0.6
+  }
+}
 
 pub fn extract_string(value: &Value, key: &str) -> Option<String> {
   if let Some(inner) = value.get(key) {
-    if let Some(text)  = inner.as_str() {
-      Some(text.to_owned())
-    } else {
-      None
-    }
+    inner.as_str().map(|text| text.to_owned())
   } else {
     None
   }
@@ -19,27 +24,15 @@ pub fn extract_string_or_empty(value: &Value, key: &str) -> String {
   extract_string(value, key).unwrap_or("".to_string())
 }
 
-pub fn extract_object_vec(value: &Value, key: &str) -> Vec<Value> {
-  if let Some(results_value) = value.get(key) {
-    if let Some(rows) = results_value.as_array() {
-      rows.to_owned()
-    } else {
-      vec![]
-    }
-  } else {
-    vec![]
-  }
-}
-
 pub fn extract_inner_results(json: &Value, key: &str, offset: usize) -> Vec<SearchResult> {
   let mut results: Vec<SearchResult> = Vec::new();
   if let Some(data_map) = json[key].as_object() {
     if let Some(inner) = data_map.get("results") {
       if let Some(rows) = inner.as_array() {
-        let mut index = offset;
-        for row in rows {
-          results.push(SearchResult::new(&row, index));
-          index += 1;
+        for (index, row) in rows.iter().enumerate() {
+          let mut result = SearchResult::new(row, offset + index);
+          result.news = key == "news";
+          results.push(result);
         }
       }
     }
@@ -50,10 +43,18 @@ pub fn extract_inner_results(json: &Value, key: &str, offset: usize) -> Vec<Sear
 pub fn extract_mojeek_results(json: &Value, key: &str, offset: usize) -> Vec<SearchResult> {
   let mut results: Vec<SearchResult> = Vec::new();
   if let Some(rows) = json[key].as_array() {
-    let mut index = offset;
-    for row in rows {
-      results.push(SearchResult::new_from_mojeek(&row, index));
-      index += 1;
+    for (index, row) in rows.iter().enumerate() {
+      results.push(SearchResult::new_from_mojeek(row, offset + index));
+    }
+  }
+  results
+}
+
+pub fn extract_textsurf_results(json: &Value, key: &str, offset: usize) -> Vec<SearchResult> {
+  let mut results: Vec<SearchResult> = Vec::new();
+  if let Some(rows) = json[key].as_array() {
+    for (index, row) in rows.iter().enumerate() {
+      results.push(SearchResult::new_from_textsurf(row, offset + index));
     }
   }
   results
@@ -75,6 +76,23 @@ pub fn extract_suggest_results(json: &Value) -> Vec<String> {
   results
 }
 
+/// Result removed by an exclusion rule, kept so that removals can be explained
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExcludedResult {
+  pub uri: String,
+  pub rule: String,
+  pub provider: SearchProvider,
+}
+
+/// Syndicated copy of a result folded into it as a near-duplicate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alternate {
+  pub uri: String,
+  pub title: String,
+  pub provider: SearchProvider,
+}
+
+#[skip_serializing_none]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SearchResult {
   pub uri: String,
@@ -82,29 +100,39 @@ pub struct SearchResult {
   pub summary: String,
   pub date: String,
   pub provider: SearchProvider,
-  pub weight: usize
+  #[serde(default)]
+  pub rank: usize,
+  pub weight: usize,
+  #[serde(default)]
+  pub news: bool,
+  pub alternates: Option<Vec<Alternate>>,
+  pub rules: Option<Vec<String>>, // allow, boost and demote rules that matched
 }
 
 impl  SearchResult {
-  pub fn new(json: &Value, weight: usize) -> Self {
-    let uri = extract_string_or_empty(&json, "url");
-    let title = extract_string_or_empty(&json, "title");
-    let summary = extract_string_or_empty(&json, "description");
-    let date = extract_string_or_empty(&json, "page_age");
+  pub fn new(json: &Value, rank: usize) -> Self {
+    let uri = extract_string_or_empty(json, "url");
+    let title = extract_string_or_empty(json, "title");
+    let summary = extract_string_or_empty(json, "description");
+    let date = extract_string_or_empty(json, "page_age");
     SearchResult {
       uri,
       title,
       summary,
       date,
       provider: SearchProvider::Brave,
-      weight: weight * 7, // greater weight, lower ranking
+      rank,
+      news: false,
+      alternates: None,
+      rules: None,
+      weight: rank_weight(SearchProvider::Brave, rank)
     }
   }
 
-  pub fn new_from_mojeek(json: &Value, weight: usize) -> Self {
-    let uri = extract_string_or_empty(&json, "url");
-    let title = extract_string_or_empty(&json, "title");
-    let summary = extract_string_or_empty(&json, "desc");
+  pub fn new_from_mojeek(json: &Value, rank: usize) -> Self {
+    let uri = extract_string_or_empty(json, "url");
+    let title = extract_string_or_empty(json, "title");
+    let summary = extract_string_or_empty(json, "desc");
     let date = chrono::Utc::now().to_rfc3339();
     SearchResult {
       uri,
@@ -112,10 +140,43 @@ impl  SearchResult {
       summary,
       date,
       provider: SearchProvider::Mojeek,
-      weight: weight * 4 // less weight, higher ranking
+      rank,
+      news: false,
+      alternates: None,
+      rules: None,
+      weight: rank_weight(SearchProvider::Mojeek, rank)
     }
   }
 
+  pub fn new_from_textsurf(json: &Value, rank: usize) -> Self {
+    let uri = extract_string_or_empty(json, "url");
+    let title = extract_string_or_empty(json, "title");
+    // full-text matches carry a highlighted snippet, with the page description as fallback
+    let summary = extract_string(json, "snippet").unwrap_or(extract_string_or_empty(json, "description"));
+    let date = extract_string(json, "date").unwrap_or(chrono::Utc::now().to_rfc3339());
+    SearchResult {
+      uri,
+      title,
+      summary,
+      date,
+      provider: SearchProvider::Text,
+      rank,
+      news: false,
+      alternates: None,
+      rules: None,
+      weight: rank_weight(SearchProvider::Text, rank)
+    }
+  }
+
+  pub fn add_alternate(&mut self, other: &SearchResult) {
+    let alternate = Alternate {
+      uri: other.uri.clone(),
+      title: other.title.clone(),
+      provider: other.provider,
+    };
+    self.alternates.get_or_insert_with(Vec::new).push(alternate);
+  }
+
   pub fn subtract_weight(&mut self, value: usize) {
     if value < self.weight {
       self.weight -= value;
@@ -126,6 +187,74 @@ impl  SearchResult {
 
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProviderState {
+  #[serde(rename = "ok")]
+  Ok,
+  #[serde(rename = "error")]
+  Error,
+  #[serde(rename = "timeout")]
+  Timeout,
+  #[serde(rename = "skipped")]
+  Skipped,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderStatus {
+  pub provider: SearchProvider,
+  pub status: ProviderState,
+  pub count: Option<usize>,
+  pub message: Option<String>,
+}
+
+impl ProviderStatus {
+  pub fn ok(provider: SearchProvider, count: usize) -> Self {
+    ProviderStatus {
+      provider,
+      status: ProviderState::Ok,
+      count: Some(count),
+      message: None
+    }
+  }
+
+  pub fn error(provider: SearchProvider, message: &str) -> Self {
+    ProviderStatus {
+      provider,
+      status: ProviderState::Error,
+      count: None,
+      message: Some(message.to_owned())
+    }
+  }
+
+  pub fn timeout(provider: SearchProvider, limit: std::time::Duration) -> Self {
+    ProviderStatus {
+      provider,
+      status: ProviderState::Timeout,
+      count: None,
+      message: Some(format!("no response within {} ms", limit.as_millis()))
+    }
+  }
+
+  pub fn quota_exhausted(provider: SearchProvider) -> Self {
+    ProviderStatus {
+      provider,
+      status: ProviderState::Skipped,
+      count: None,
+      message: Some("API budget nearly used up".to_owned())
+    }
+  }
+
+  pub fn paging_unsupported(provider: SearchProvider) -> Self {
+    ProviderStatus {
+      provider,
+      status: ProviderState::Skipped,
+      count: None,
+      message: Some("paging not supported".to_owned())
+    }
+  }
+}
+
 #[skip_serializing_none]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResultSet {
@@ -137,7 +266,11 @@ pub struct ResultSet {
   pub cc: Option<String>,
   pub page: u16,
   pub removed: usize,
-  pub cached: bool
+  pub cached: bool,
+  #[serde(default)]
+  pub stale: bool,
+  pub providers: Option<Vec<ProviderStatus>>,
+  pub excluded: Option<Vec<ExcludedResult>>, // only shown with debug=1 or explain=1
 }
 
 impl  ResultSet {
@@ -145,12 +278,12 @@ impl  ResultSet {
     
   pub fn new(json: &Value, options: &BraveSearchOptions) -> Self {
     let is_obj = json.is_object();
-    let keys = if is_obj { json.as_object().unwrap().keys().into_iter().map(|k| k.as_str()).collect::<Vec<&str>>() } else { vec![] };
+    let keys = if is_obj { json.as_object().unwrap().keys().map(|k| k.as_str()).collect::<Vec<&str>>() } else { vec![] };
     let valid = keys.contains(&"mixed") && (keys.contains(&"news") || keys.contains(&"web"));
     let offset = options.offset.unwrap_or(0) as usize;
     let mut results: Vec<SearchResult> = extract_inner_results(json, "news", offset);
     let web_results: Vec<SearchResult> = extract_inner_results(json, "web", offset + results.len());
-    if web_results.len() > 0 {
+    if !web_results.is_empty() {
       for result in web_results {
         results.push(result);
       }
@@ -169,17 +302,20 @@ impl  ResultSet {
       cc,
       lang,
       removed: 0,
-      cached: false
+      cached: false,
+      stale: false,
+      providers: None,
+      excluded: None
     }
   }
 
   pub fn new_from_mojeek(json: &Value, options: &BraveSearchOptions) -> Self {
     let is_obj = json.is_object();
-    let keys = if is_obj { json.as_object().unwrap().keys().into_iter().map(|k| k.as_str()).collect::<Vec<&str>>() } else { vec![] };
-    let valid = false;
+    let keys = if is_obj { json.as_object().unwrap().keys().map(|k| k.as_str()).collect::<Vec<&str>>() } else { vec![] };
     let offset = options.offset.unwrap_or(0) as usize;
     if keys.contains(&"response") {
       if let Some(_data_map) = json["response"].as_object() {
+        let valid = true;
         let results: Vec<SearchResult> = extract_mojeek_results(&json["response"], "results", offset);
         let count = results.len();
         let ts = get_timestamp();
@@ -195,7 +331,10 @@ impl  ResultSet {
           cc,
           lang,
           removed: 0,
-          cached: false
+          cached: false,
+          stale: false,
+          providers: None,
+      excluded: None
         }
       } else {
         ResultSet::empty()  
@@ -206,6 +345,31 @@ impl  ResultSet {
     
   }
     
+  pub fn new_from_textsurf(json: &Value, options: &BraveSearchOptions) -> Self {
+    let valid = json["results"].is_array();
+    let offset = options.offset.unwrap_or(0) as usize;
+    let results: Vec<SearchResult> = extract_textsurf_results(json, "results", offset);
+    let count = results.len();
+    let ts = get_timestamp();
+    let page = options.page();
+    let cc = options.country_code();
+    let lang = options.lang();
+    ResultSet {
+      valid,
+      count,
+      results,
+      ts,
+      page,
+      cc,
+      lang,
+      removed: 0,
+      cached: false,
+      stale: false,
+      providers: None,
+      excluded: None
+    }
+  }
+
   pub fn empty() -> Self {
     ResultSet {
       valid: false,
@@ -213,10 +377,13 @@ impl  ResultSet {
       results: Vec::new(),
       ts: 0,
       cached: false,
+      stale: false,
       lang: None,
       cc: None,
       removed: 0,
-      page: 0
+      page: 0,
+      providers: None,
+      excluded: None
     }
   }
 
@@ -229,28 +396,101 @@ impl  ResultSet {
     self.to_owned()
   }
 
-  pub fn merge_results(&mut self, other_set: ResultSet) {
+  pub fn set_provider_statuses(&mut self, statuses: Vec<ProviderStatus>) {
+    self.providers = if statuses.is_empty() { None } else { Some(statuses) };
+  }
+
+  pub fn all_providers_ok(&self) -> bool {
+    self.providers.as_ref().is_none_or(|statuses| statuses.iter().all(|s| s.status == ProviderState::Ok))
+  }
+
+  /// Merge the results of other providers into this set, deduplicating by canonical URL
+  pub fn merge_results(&mut self, other_sets: Vec<ResultSet>, strategy: MergeStrategy) {
     self.ts = get_timestamp();
-    let current_uris = self.results.clone().into_iter().map(|row| row.uri).collect::<Vec<String>>();
-    for row in other_set.results {
-      if let Some(current_index) = find_position_in_strings(&current_uris, &row.uri) {
-        if let Some(current_row) = self.results.get_mut(current_index.to_owned()) {
-          current_row.subtract_weight(row.weight);
-        }
+    let mut providers: Vec<SearchProvider> = Vec::new();
+    let mut keys: Vec<String> = Vec::new();
+    let mut groups: Vec<Vec<SearchResult>> = Vec::new();
+    let rows = std::mem::take(&mut self.results).into_iter().chain(other_sets.into_iter().flat_map(|set| set.results));
+    for row in rows {
+      if !providers.contains(&row.provider) {
+        providers.push(row.provider);
+      }
+      let key = canonical_url(&row.uri);
+      if let Some(index) = find_position_in_strings(&keys, &key) {
+        groups[index].push(row);
       } else {
-        self.results.push(row);
+        keys.push(key);
+        groups.push(vec![row]);
       }
     }
-    self.results.sort_by(|a,b| a.weight.cmp(&b.weight));
+    self.results = merge_groups(groups, strategy, &providers);
     self.count = self.results.len();
   }
 
-  pub fn exclude_by_patterns(&mut self) {
+  /// Fold syndicated copies with similar titles and summaries into the
+  /// highest ranked result, listing the copies as alternates
+  pub fn fold_near_duplicates(&mut self, mode: FoldMode) {
+    if mode == FoldMode::Off {
+      return;
+    }
+    let threshold = near_duplicate_threshold();
+    let mut kept: Vec<(SearchResult, HashSet<u64>)> = Vec::new();
+    for row in std::mem::take(&mut self.results) {
+      let eligible = mode == FoldMode::All || row.news;
+      let row_shingles = shingles(&[row.title.as_str(), row.summary.as_str()].join(" "));
+      let primary = if eligible {
+        kept.iter_mut().find(|(other, other_shingles)| (mode == FoldMode::All || other.news) && jaccard(&row_shingles, other_shingles) >= threshold)
+      } else {
+        None
+      };
+      match primary {
+        Some((other, _)) => other.add_alternate(&row),
+        None => kept.push((row, row_shingles)),
+      }
+    }
+    self.results = kept.into_iter().map(|(row, _)| row).collect();
+    self.count = self.results.len();
+  }
+
+  /// Remove excluded and, with an allowlist, unlisted results, then apply boost and demote rules
+  pub fn apply_rules(&mut self, matcher: &ExclusionMatcher) {
     let full_count = self.count;
-    let pattern_strings = get_exclusion_pattern_strings();
-    self.results = self.results.clone().into_iter().filter(|row| !uri_is_excluded(&pattern_strings, &row.uri)).collect();
+    if matcher.is_empty() {
+      return;
+    }
+    let mut reweighted = false;
+    let mut excluded = Vec::new();
+    let mut multipliers = Vec::with_capacity(self.results.len());
+    self.results.retain_mut(|row| match matcher.evaluate(row) {
+      RuleVerdict::Exclude(name) => {
+        excluded.push(ExcludedResult { uri: row.uri.clone(), rule: name.to_owned(), provider: row.provider });
+        false
+      },
+      RuleVerdict::Keep(multiplier, names) => {
+        multipliers.push(multiplier);
+        reweighted = reweighted || multiplier != 1.0;
+        if !names.is_empty() {
+          row.rules = Some(names.into_iter().map(|name| name.to_owned()).collect());
+        }
+        true
+      }
+    });
+    if reweighted {
+      // weights start at 0 for position-based merges, so scale from 1. Neighbours often tie
+      // after scaling, so ties go to the boosted result and against the demoted one.
+      let mut scored = self.results.drain(..).zip(multipliers).map(|(mut row, multiplier)| {
+        let score = (row.weight + 1) as f64 * multiplier;
+        row.weight = (score.round() as usize).saturating_sub(1);
+        (score, multiplier, row)
+      }).collect::<Vec<(f64, f64, SearchResult)>>();
+      scored.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+      self.results = scored.into_iter().map(|(_, _, row)| row).collect();
+    }
     self.count = self.results.len();
-    self.removed = full_count - self.count
+    self.removed = full_count - self.count;
+    if !excluded.is_empty() {
+      self.excluded = Some(excluded);
+    }
   }
 
 }
@@ -272,7 +512,7 @@ impl  AutoSuggestResultSet {
     
   pub fn new(json: &Value, options: &BraveSearchOptions) -> Self {
     let is_obj = json.is_object();
-    let keys = if is_obj { json.as_object().unwrap().keys().into_iter().map(|k| k.as_str()).collect::<Vec<&str>>() } else { vec![] };
+    let keys = if is_obj { json.as_object().unwrap().keys().map(|k| k.as_str()).collect::<Vec<&str>>() } else { vec![] };
     let valid = keys.contains(&"results");
     let results: Vec<String> = extract_suggest_results(json);
 
@@ -314,3 +554,108 @@ impl  AutoSuggestResultSet {
 
 }
 
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use crate::exclusions::UrlPattern;
+  use super::*;
+
+  /// Results of one provider merged by position, so weights are 0, 1, 2...
+  fn ranked(uris: &[&str]) -> ResultSet {
+    let groups = uris.iter().enumerate().map(|(rank, uri)| vec![SearchResult::new(&json!({ "url": uri }), rank)]).collect();
+    let mut result = ResultSet::empty();
+    result.results = merge_groups(groups, MergeStrategy::Rrf, &[SearchProvider::Brave]);
+    result.count = result.results.len();
+    result
+  }
+
+  fn matcher(rules: Value) -> ExclusionMatcher {
+    ExclusionMatcher::new(&serde_json::from_value::<Vec<UrlPattern>>(rules).unwrap()).unwrap()
+  }
+
+  fn uris(result: &ResultSet) -> Vec<&str> {
+    result.results.iter().map(|row| row.uri.as_str()).collect()
+  }
+
+  const STORY: &str = "Storm hits the coast with heavy rain and strong wind tonight";
+
+  fn story(uri: &str, title: &str, news: bool, provider: SearchProvider) -> SearchResult {
+    let mut row = match provider {
+      SearchProvider::Mojeek => SearchResult::new_from_mojeek(&json!({ "url": uri, "title": title }), 0),
+      _ => SearchResult::new(&json!({ "url": uri, "title": title }), 0),
+    };
+    row.news = news;
+    row
+  }
+
+  fn folded(rows: Vec<SearchResult>, mode: FoldMode) -> ResultSet {
+    let mut result = ResultSet::empty();
+    result.count = rows.len();
+    result.results = rows;
+    result.fold_near_duplicates(mode);
+    result
+  }
+
+  #[test]
+  fn near_duplicate_news_is_folded_into_alternates() {
+    let result = folded(vec![
+      story("https://a.com/storm", STORY, true, SearchProvider::Brave),
+      story("https://b.com/storm", &format!("{} in the north", STORY), true, SearchProvider::Mojeek),
+    ], FoldMode::News);
+    assert_eq!(uris(&result), vec!["https://a.com/storm"]);
+    assert_eq!(result.count, 1);
+    let alternates = result.results[0].alternates.clone().unwrap();
+    assert_eq!(alternates.len(), 1);
+    assert_eq!(alternates[0].uri, "https://b.com/storm");
+    assert!(alternates[0].title.ends_with("in the north"));
+    assert_eq!(alternates[0].provider, SearchProvider::Mojeek);
+  }
+
+  #[test]
+  fn news_mode_leaves_web_results_alone() {
+    let rows = || vec![
+      story("https://a.com/storm", STORY, false, SearchProvider::Brave),
+      story("https://b.com/storm", STORY, false, SearchProvider::Brave),
+      story("https://c.com/storm", STORY, true, SearchProvider::Brave),
+    ];
+    assert_eq!(folded(rows(), FoldMode::News).count, 3);
+    assert_eq!(folded(rows(), FoldMode::All).count, 1);
+    assert_eq!(folded(rows(), FoldMode::Off).count, 3);
+  }
+
+  #[test]
+  fn results_below_the_threshold_are_kept_apart() {
+    // the first five words give three shared shingles out of fifteen
+    let result = folded(vec![
+      story("https://a.com/storm", STORY, true, SearchProvider::Brave),
+      story("https://b.com/storm", "Storm hits the coast with lanterns glowing over calm harbour towns", true, SearchProvider::Brave),
+    ], FoldMode::All);
+    assert_eq!(result.count, 2);
+    assert!(result.results.iter().all(|row| row.alternates.is_none()));
+  }
+
+  #[test]
+  fn demoting_the_top_result_moves_it_down() {
+    let mut result = ranked(&["https://a.com", "https://b.com", "https://c.com"]);
+    result.apply_rules(&matcher(json!([{ "name": "A", "pattern": "a\\.com", "action": "demote" }])));
+    assert_eq!(uris(&result), vec!["https://b.com", "https://a.com", "https://c.com"]);
+  }
+
+  #[test]
+  fn boosting_the_second_result_moves_it_up() {
+    let mut result = ranked(&["https://a.com", "https://b.com", "https://c.com"]);
+    result.apply_rules(&matcher(json!([{ "name": "B", "pattern": "b\\.com", "action": "boost" }])));
+    assert_eq!(uris(&result), vec!["https://b.com", "https://a.com", "https://c.com"]);
+    assert_eq!(result.results[0].rules, Some(vec!["B".to_owned()]));
+  }
+
+  #[test]
+  fn excluded_results_are_listed_and_counted() {
+    let mut result = ranked(&["https://a.com", "https://b.com"]);
+    result.apply_rules(&matcher(json!([{ "name": "A", "pattern": "a\\.com" }])));
+    assert_eq!(uris(&result), vec!["https://b.com"]);
+    assert_eq!((result.count, result.removed), (1, 1));
+    assert_eq!(result.excluded.map(|rows| rows[0].rule.clone()), Some("A".to_owned()));
+  }
+}
@@ -2,7 +2,14 @@ use axum::extract::Query;
 use serde::{Serialize, Deserialize};
 use serde_with::skip_serializing_none;
 use slug::slugify;
-use crate::constants::match_country_code;
+use crate::{constants::match_country_code, exclusions::DEFAULT_PROFILE};
+
+fn profile_key(profile: Option<&str>) -> String {
+  match profile.map(|p| p.trim().to_lowercase()) {
+    Some(p) if !p.is_empty() => p,
+    _ => DEFAULT_PROFILE.to_owned()
+  }
+}
 
 #[skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -14,6 +21,17 @@ pub struct QueryParams {
   pub p: Option<i64>, // page=1 is the first
   pub cached: Option<i16>, 
   pub mode: Option<String>, 
+  pub merge: Option<String>,
+  pub fold: Option<String>, // near-duplicate folding: news (default), all or off
+  pub profile: Option<String>, // exclusion profile, unless the client key sets one
+  pub debug: Option<u8>,
+  pub explain: Option<u8>, // list excluded results with the rule that removed them
+}
+
+impl QueryParams {
+  pub fn explain(&self) -> bool {
+    self.debug.unwrap_or(0) > 0 || self.explain.unwrap_or(0) > 0
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -24,6 +42,9 @@ pub struct BraveSearchOptions {
   pub language: Option<String>,
   pub offset: Option<u16>,
   pub mode: SearchProviderMode, 
+  pub merge: MergeStrategy,
+  pub fold: FoldMode,
+  pub profile: String,
 }
 
 impl BraveSearchOptions {
@@ -35,27 +56,41 @@ impl BraveSearchOptions {
     let offset_i64 = params.p.unwrap_or(0) - 1;
     let offset = if offset_i64 >= 0 && offset_i64 <= u16::MAX as i64 { Some(offset_i64 as u16) } else { None };
     let lang_str = params.lang.clone().unwrap_or("".to_string());
-    let language = if lang_str.len() > 0 && lang_str.len() < 4 { Some(lang_str.to_lowercase()) } else { None };
+    let language = if !lang_str.is_empty() && lang_str.len() < 4 { Some(lang_str.to_lowercase()) } else { None };
     let cc = match cc_opt {
       Some(cc_key) => match_country_code(&cc_key),
       _ => None
     };
     let mode_key = params.mode.clone().unwrap_or("core".to_string());
     let mode = SearchProviderMode::from_key(&mode_key);
+    let merge = MergeStrategy::from_opt_key(params.merge.clone());
+    let fold = FoldMode::from_opt_key(params.fold.clone());
+    let profile = profile_key(params.profile.as_deref());
     BraveSearchOptions {
       q,
       safesearch,
       cc,
       language,
       offset,
-      mode
+      mode,
+      merge,
+      fold,
+      profile
+    }
+  }
+
+  /// A profile set on the client key overrides the one requested
+  pub fn set_profile(&mut self, profile: Option<&str>) {
+    if profile.is_some() {
+      self.profile = profile_key(profile);
     }
   }
 
   pub fn to_cache_key(&self, mode: SearchProviderMode) -> String {
     let safe_search_key = self.safesearch.to_short();
-    let second_param = mode.to_param_key(&safe_search_key);
-    slugify(&[
+    // folding is applied on read, so one entry serves every fold mode
+    let second_param = [mode.to_param_key(&safe_search_key), self.merge.to_key().to_owned()].concat();
+    slugify([
         "cs",
         &self.q,
         &second_param,
@@ -66,7 +101,7 @@ impl BraveSearchOptions {
   }
 
   pub fn to_suggest_cache_key(&self) -> String {
-    slugify(&[
+    slugify([
         "br_sugg",
         &self.q,
         self.cc.clone().unwrap_or("all".to_string()).as_str(),
@@ -141,6 +176,23 @@ impl BraveSearchOptions {
     tuples
   }
 
+  pub fn to_textsurf_tuples(&self) -> Vec<(&str, String)> {
+    let mut tuples: Vec<(&str, String)> = vec![
+      ("q", self.q.clone()),
+      ("limit", 20.to_string()),
+    ];
+    if self.cc.is_some() {
+      tuples.push(("cc", self.cc_val()));
+    }
+    if self.offset.is_some() {
+      tuples.push(("offset", (self.offset.unwrap_or(0) as u32 * 20).to_string()));
+    }
+    if self.language.is_some() {
+      tuples.push(("lang", self.lang_code("")));
+    }
+    tuples
+  }
+
   pub fn to_suggest_tuples(&self) -> Vec<(&str, String)> {
     let mut tuples: Vec<(&str, String)> = vec![
       ("q", self.q.clone()),
@@ -199,7 +251,7 @@ impl SafeMode {
 
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SearchProvider {
   #[serde(rename = "textsurf")]
   Text, // TextSurf
@@ -209,7 +261,7 @@ pub enum SearchProvider {
   Mojeek,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SearchProviderMode {
   #[serde(rename = "all")]
   All,
@@ -235,13 +287,34 @@ impl SearchProviderMode {
     }
   }
 
-  pub fn search_mojeek(&self) -> bool {
+  /// Strict, case-insensitive variant of from_key for configured mode names, None if unknown
+  pub fn parse_key(key: &str) -> Option<Self> {
+    match key.trim().to_lowercase().as_str() {
+      "all" => Some(SearchProviderMode::All),
+      "text" | "fulltext" => Some(SearchProviderMode::FullText),
+      "core" => Some(SearchProviderMode::Core),
+      "brave" => Some(SearchProviderMode::Brave),
+      "mojeek" => Some(SearchProviderMode::Mojeek),
+      _ => None
+    }
+  }
+
+  /// The exact set of providers named by this mode
+  pub fn providers(&self) -> Vec<SearchProvider> {
     match self {
-      SearchProviderMode::Mojeek | SearchProviderMode::Core | SearchProviderMode::All => true,
-      _ => false
+      SearchProviderMode::All => vec![SearchProvider::Brave, SearchProvider::Mojeek, SearchProvider::Text],
+      SearchProviderMode::Core => vec![SearchProvider::Brave, SearchProvider::Mojeek],
+      SearchProviderMode::FullText => vec![SearchProvider::Text],
+      SearchProviderMode::Brave => vec![SearchProvider::Brave],
+      SearchProviderMode::Mojeek => vec![SearchProvider::Mojeek],
     }
   }
 
+  /// Whether a backend for the given provider should be queried in this mode
+  pub fn includes(&self, provider: SearchProvider) -> bool {
+    self.providers().contains(&provider)
+  }
+
   pub fn param_key(&self) -> Option<&'static str> {
     let key = match self {
       SearchProviderMode::All => "all",
@@ -250,14 +323,14 @@ impl SearchProviderMode {
       SearchProviderMode::Mojeek => "mojeek",
       _ => ""
     };
-    if key.len() > 0 {
+    if !key.is_empty() {
       Some(key)
     } else {
       None
     }
   }
 
-  pub fn to_param_key(&self, suffix: &str) -> String {
+  pub fn to_param_key(self, suffix: &str) -> String {
     let mut parts = vec![suffix];
     if let Some(mode) = self.param_key() {
       parts.push(mode);
@@ -265,4 +338,71 @@ impl SearchProviderMode {
     parts.concat()
   }
 
-}
\ No newline at end of file
+}
+
+/// How results from several providers are combined into one ranking
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MergeStrategy {
+  #[serde(rename = "legacy")]
+  Legacy, // weighted ranks, duplicates lift the primary result
+  #[serde(rename = "rrf")]
+  Rrf, // reciprocal rank fusion
+  #[serde(rename = "borda")]
+  Borda, // weighted Borda count
+  #[serde(rename = "interleave")]
+  Interleave, // round-robin across providers
+}
+
+impl MergeStrategy {
+  pub fn from_key(key: &str) -> Option<Self> {
+    match key.to_lowercase().as_str() {
+      "legacy" | "weighted" => Some(MergeStrategy::Legacy),
+      "rrf" | "fusion" => Some(MergeStrategy::Rrf),
+      "borda" => Some(MergeStrategy::Borda),
+      "interleave" | "rr" | "roundrobin" => Some(MergeStrategy::Interleave),
+      _ => None
+    }
+  }
+
+  /// Per-request key, falling back to MERGE_STRATEGY and then to legacy weighting
+  pub fn from_opt_key(key: Option<String>) -> Self {
+    key.and_then(|k| MergeStrategy::from_key(&k))
+      .or_else(|| dotenv::var("MERGE_STRATEGY").ok().and_then(|k| MergeStrategy::from_key(&k)))
+      .unwrap_or(MergeStrategy::Legacy)
+  }
+
+  pub fn to_key(self) -> &'static str {
+    match self {
+      MergeStrategy::Legacy => "",
+      MergeStrategy::Rrf => "rrf",
+      MergeStrategy::Borda => "borda",
+      MergeStrategy::Interleave => "interleave",
+    }
+  }
+}
+
+/// Which results are checked for near-duplicate copies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FoldMode {
+  #[serde(rename = "off")]
+  Off,
+  #[serde(rename = "news")]
+  News, // only results from the Brave news block
+  #[serde(rename = "all")]
+  All,
+}
+
+impl FoldMode {
+  pub fn from_key(key: &str) -> Self {
+    match key.to_lowercase().as_str() {
+      "off" | "none" | "0" => FoldMode::Off,
+      "all" | "2" => FoldMode::All,
+      _ => FoldMode::News,
+    }
+  }
+
+  pub fn from_opt_key(key: Option<String>) -> Self {
+    let ref_key = key.unwrap_or("news".to_string());
+    FoldMode::from_key(&ref_key)
+  }
+}
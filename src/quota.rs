@@ -0,0 +1,135 @@
+use std::{collections::HashMap, sync::{Mutex, OnceLock}, time::Duration};
+use chrono::Utc;
+use reqwest::header::HeaderMap;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+use crate::{cache::get_timestamp, stores::CacheStore};
+
+/// Upstream API keys whose usage is metered. Brave search and suggest use separate keys.
+pub const METERS: [&str; 4] = ["brave", "brave_suggest", "mojeek", "textsurf"];
+
+/// Latest rate-limit window reported by an upstream in its response headers.
+/// Brave sends a per-second and a per-month value, e.g. `X-RateLimit-Remaining: 1, 14012`.
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+  pub limit: Option<u64>,
+  pub remaining: Option<u64>,
+  pub reset: Option<u64>,
+  pub per_second_limit: Option<u64>,
+  pub updated: i64,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeterUsage {
+  pub meter: String,
+  pub used: u64, // upstream calls made this calendar month
+  pub budget: Option<u64>,
+  pub rate_limit: Option<RateLimit>,
+  pub exhausted: bool,
+}
+
+fn latest_limits() -> &'static Mutex<HashMap<String, RateLimit>> {
+  static LATEST: OnceLock<Mutex<HashMap<String, RateLimit>>> = OnceLock::new();
+  LATEST.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn header_values(headers: &HeaderMap, key: &str) -> Vec<u64> {
+  headers.get(key).and_then(|v| v.to_str().ok())
+    .map(|v| v.split(',').filter_map(|part| part.trim().parse::<u64>().ok()).collect())
+    .unwrap_or_default()
+}
+
+/// Keep the rate-limit headers of an upstream response, if it sent any
+pub fn record_rate_limit(meter: &str, headers: &HeaderMap) {
+  let limits = header_values(headers, "x-ratelimit-limit");
+  if limits.is_empty() {
+    return;
+  }
+  let remaining = header_values(headers, "x-ratelimit-remaining");
+  let reset = header_values(headers, "x-ratelimit-reset");
+  // with several windows the last one is the longest, e.g. the monthly quota
+  let rate_limit = RateLimit {
+    limit: limits.last().copied(),
+    remaining: remaining.last().copied(),
+    reset: reset.last().copied(),
+    per_second_limit: if limits.len() > 1 { limits.first().copied() } else { None },
+    updated: get_timestamp(),
+  };
+  latest_limits().lock().unwrap_or_else(|e| e.into_inner()).insert(meter.to_owned(), rate_limit);
+}
+
+fn usage_key(meter: &str) -> String {
+  format!("quota_{}_{}", meter, Utc::now().format("%Y%m"))
+}
+
+/// Monthly budget for a meter, e.g. BRAVE_MONTHLY_BUDGET=15000
+fn monthly_budget(meter: &str) -> Option<u64> {
+  let env_key = format!("{}_MONTHLY_BUDGET", meter.to_uppercase());
+  dotenv::var(env_key).ok().and_then(|v| v.parse::<u64>().ok()).filter(|budget| *budget > 0)
+}
+
+/// Share of a quota held back, from QUOTA_RESERVE_PCT (default 5)
+fn reserve_share() -> f64 {
+  let pct = dotenv::var("QUOTA_RESERVE_PCT").ok().and_then(|v| v.parse::<f64>().ok()).unwrap_or(5.0);
+  pct.clamp(0.0, 100.0) / 100.0
+}
+
+fn local_counts() -> &'static Mutex<HashMap<String, u64>> {
+  static COUNTS: OnceLock<Mutex<HashMap<String, u64>>> = OnceLock::new();
+  COUNTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Count one call against a meter for the current month and return the new total.
+/// Counters live in the cache store so that all instances share them,
+/// with a per-process count if the store cannot count.
+pub async fn increment_usage(store: &dyn CacheStore, meter: &str) -> u64 {
+  let key = usage_key(meter);
+  match store.incr(&key, Some(Duration::from_secs(32 * 24 * 60 * 60))).await {
+    Ok(count) => count,
+    Err(error) => {
+      tracing::debug!("cannot count {} usage in {} store: {}", meter, store.name(), error);
+      let mut counts = local_counts().lock().unwrap_or_else(|e| e.into_inner());
+      let count = counts.entry(key).or_insert(0);
+      *count += 1;
+      *count
+    }
+  }
+}
+
+/// Count one upstream call
+pub async fn record_call(store: &dyn CacheStore, meter: &str) {
+  increment_usage(store, meter).await;
+}
+
+pub async fn meter_usage(store: &dyn CacheStore, meter: &str) -> MeterUsage {
+  let key = usage_key(meter);
+  let stored = store.get_counter(&key).await.ok().flatten().and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+  let local = local_counts().lock().unwrap_or_else(|e| e.into_inner()).get(&key).copied().unwrap_or(0);
+  let used = stored.max(local);
+  let budget = monthly_budget(meter);
+  let rate_limit = latest_limits().lock().unwrap_or_else(|e| e.into_inner()).get(meter).cloned();
+  let reserve = reserve_share();
+  let over_budget = budget.is_some_and(|b| used as f64 >= b as f64 * (1.0 - reserve));
+  // a reported window no longer applies once its reset time has passed
+  let upstream_low = rate_limit.as_ref().is_some_and(|rl| match (rl.limit, rl.remaining) {
+    (Some(limit), Some(remaining)) => {
+      let current = rl.reset.is_none_or(|reset| rl.updated + reset as i64 > get_timestamp());
+      current && remaining as f64 <= limit as f64 * reserve
+    },
+    _ => false
+  });
+  MeterUsage {
+    meter: meter.to_owned(),
+    used,
+    budget,
+    rate_limit,
+    exhausted: over_budget || upstream_low,
+  }
+}
+
+pub async fn is_exhausted(store: &dyn CacheStore, meter: &str) -> bool {
+  meter_usage(store, meter).await.exhausted
+}
@@ -0,0 +1,234 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+use axum::{
+  extract::{ConnectInfo, State},
+  http::{HeaderMap, Request},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+
+use crate::{clients::ClientKey, errors::AppError, state::AppState};
+
+/// Buckets idle for longer than this are dropped when the map is pruned
+const IDLE_BUCKET_SECS: u64 = 600;
+
+/// Number of checks between prunes of idle buckets
+const PRUNE_INTERVAL: u64 = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+  pub per_minute: f64,
+  pub burst: f64,
+}
+
+impl Limit {
+  /// Limit from an env var holding requests per minute, e.g. RATE_LIMIT_SEARCH_IP=60.
+  /// Zero disables the limit.
+  fn from_env(key: &str, default_per_minute: f64, burst: f64) -> Option<Self> {
+    let per_minute = dotenv::var(key).ok().and_then(|v| v.parse::<f64>().ok()).unwrap_or(default_per_minute);
+    if per_minute > 0.0 {
+      Some(Limit { per_minute, burst: burst.max(1.0) })
+    } else {
+      None
+    }
+  }
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ScopeLimits {
+  ip: Option<Limit>,
+  key: Option<Limit>,
+}
+
+/// Token-bucket limiter for inbound requests, keyed by route scope and client IP or key
+pub struct RateLimiter {
+  search: ScopeLimits,
+  suggest: ScopeLimits,
+  failures: Option<Limit>, // failed client or admin key attempts per IP
+  buckets: Mutex<HashMap<String, Bucket>>,
+  calls: AtomicU64,
+}
+
+impl RateLimiter {
+  pub fn from_env() -> Self {
+    let burst = dotenv::var("RATE_LIMIT_BURST").ok().and_then(|v| v.parse::<f64>().ok()).unwrap_or(10.0);
+    RateLimiter {
+      search: ScopeLimits {
+        ip: Limit::from_env("RATE_LIMIT_SEARCH_IP", 60.0, burst),
+        key: Limit::from_env("RATE_LIMIT_SEARCH_KEY", 600.0, burst),
+      },
+      suggest: ScopeLimits {
+        ip: Limit::from_env("RATE_LIMIT_SUGGEST_IP", 300.0, burst),
+        key: Limit::from_env("RATE_LIMIT_SUGGEST_KEY", 3000.0, burst),
+      },
+      failures: Limit::from_env("RATE_LIMIT_FAILED_AUTH_IP", 10.0, burst),
+      buckets: Mutex::new(HashMap::new()),
+      calls: AtomicU64::new(0),
+    }
+  }
+
+  /// Take a token from the bucket, or return the seconds until one is available
+  fn take(&self, bucket_key: String, limit: Limit) -> Result<(), u64> {
+    self.spend(bucket_key, limit, 1.0)
+  }
+
+  /// Refill the bucket and remove the given number of tokens if it holds at least one
+  fn spend(&self, bucket_key: String, limit: Limit, cost: f64) -> Result<(), u64> {
+    let now = Instant::now();
+    let rate_per_sec = limit.per_minute / 60.0;
+    let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+    let bucket = buckets.entry(bucket_key).or_insert(Bucket { tokens: limit.burst, updated: now });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate_per_sec).min(limit.burst);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= cost;
+      Ok(())
+    } else {
+      Err(((1.0 - bucket.tokens) / rate_per_sec).ceil().max(1.0) as u64)
+    }
+  }
+
+  /// Refuse every key attempt from an IP whose failed attempts are used up, so that
+  /// a throttled client cannot tell a right guess from a wrong one
+  pub fn check_failures(&self, ip: &str) -> Result<(), AppError> {
+    match self.failures {
+      Some(limit) => self.spend(format!("failed:ip:{}", ip), limit, 0.0).map_err(AppError::RateLimited),
+      None => Ok(())
+    }
+  }
+
+  pub fn record_failure(&self, ip: &str) {
+    if let Some(limit) = self.failures {
+      let _ = self.take(format!("failed:ip:{}", ip), limit);
+    }
+  }
+
+  fn prune(&self) {
+    let calls = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+    if calls.is_multiple_of(PRUNE_INTERVAL) {
+      let idle = Duration::from_secs(IDLE_BUCKET_SECS);
+      self.buckets.lock().unwrap_or_else(|e| e.into_inner()).retain(|_, bucket| bucket.updated.elapsed() < idle);
+    }
+  }
+
+  pub fn check(&self, path: &str, ip: &str, client_key: Option<&str>) -> Result<(), AppError> {
+    let (scope, limits) = match path {
+      "/search" => ("search", self.search),
+      "/suggest" => ("suggest", self.suggest),
+      _ => return Ok(())
+    };
+    self.prune();
+    // requests with a client key are limited per key, others per IP
+    let result = match (client_key, limits.key) {
+      (Some(key), Some(limit)) => self.take(format!("{}:key:{}", scope, key), limit),
+      (Some(_), None) => Ok(()),
+      (None, _) => match limits.ip {
+        Some(limit) => self.take(format!("{}:ip:{}", scope, ip), limit),
+        None => Ok(())
+      }
+    };
+    result.map_err(AppError::RateLimited)
+  }
+}
+
+/// Client IP, taken from X-Forwarded-For only when TRUST_FORWARDED_FOR=1 as that header can be spoofed
+pub fn client_ip(headers: &HeaderMap, addr: &SocketAddr) -> String {
+  let trust_forwarded = dotenv::var("TRUST_FORWARDED_FOR").unwrap_or_default() == "1";
+  let forwarded = headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()).and_then(|v| v.split(',').next());
+  match forwarded {
+    Some(ip) if trust_forwarded => ip.trim().to_owned(),
+    _ => addr.ip().to_string()
+  }
+}
+
+pub async fn rate_limit<B>(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, request: Request<B>, next: Next<B>) -> Response {
+  let ip = client_ip(request.headers(), &addr);
+  // only keys resolved by the authentication layer get their own bucket
+  let client_key = request.extensions().get::<ClientKey>().map(|client| client.key.clone());
+  match state.limiter.check(request.uri().path(), &ip, client_key.as_deref()) {
+    Ok(()) => next.run(request).await,
+    Err(error) => error.into_response(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limiter(limit: Limit) -> RateLimiter {
+    let limits = ScopeLimits { ip: Some(limit), key: Some(limit) };
+    RateLimiter { search: limits, suggest: limits, failures: Some(limit), buckets: Mutex::new(HashMap::new()), calls: AtomicU64::new(0) }
+  }
+
+  /// Move the last update of a bucket back in time, as if the time had passed
+  fn wind_back(limiter: &RateLimiter, bucket_key: &str, secs: u64) {
+    let mut buckets = limiter.buckets.lock().unwrap();
+    let bucket = buckets.get_mut(bucket_key).unwrap();
+    bucket.updated -= Duration::from_secs(secs);
+  }
+
+  #[test]
+  fn allows_a_burst_then_reports_retry_after() {
+    let limit = Limit { per_minute: 6.0, burst: 2.0 };
+    let limiter = limiter(limit);
+    assert_eq!(limiter.take("a".to_owned(), limit), Ok(()));
+    assert_eq!(limiter.take("a".to_owned(), limit), Ok(()));
+    // one token every 10 seconds
+    assert_eq!(limiter.take("a".to_owned(), limit), Err(10));
+  }
+
+  #[test]
+  fn refills_tokens_over_time_up_to_the_burst() {
+    let limit = Limit { per_minute: 60.0, burst: 2.0 };
+    let limiter = limiter(limit);
+    assert_eq!(limiter.take("a".to_owned(), limit), Ok(()));
+    assert_eq!(limiter.take("a".to_owned(), limit), Ok(()));
+    assert!(limiter.take("a".to_owned(), limit).is_err());
+    wind_back(&limiter, "a", 1);
+    assert_eq!(limiter.take("a".to_owned(), limit), Ok(()));
+    assert!(limiter.take("a".to_owned(), limit).is_err());
+    // an idle bucket holds no more than the burst
+    wind_back(&limiter, "a", 600);
+    assert_eq!(limiter.take("a".to_owned(), limit), Ok(()));
+    assert_eq!(limiter.take("a".to_owned(), limit), Ok(()));
+    assert!(limiter.take("a".to_owned(), limit).is_err());
+  }
+
+  #[test]
+  fn retry_after_is_at_least_one_second() {
+    let limit = Limit { per_minute: 600.0, burst: 1.0 };
+    let limiter = limiter(limit);
+    assert_eq!(limiter.take("a".to_owned(), limit), Ok(()));
+    assert_eq!(limiter.take("a".to_owned(), limit), Err(1));
+  }
+
+  #[test]
+  fn failed_attempts_block_an_ip_once_used_up() {
+    let limiter = limiter(Limit { per_minute: 1.0, burst: 2.0 });
+    assert!(limiter.check_failures("10.0.0.1").is_ok());
+    // checking alone does not use up attempts
+    assert!(limiter.check_failures("10.0.0.1").is_ok());
+    limiter.record_failure("10.0.0.1");
+    limiter.record_failure("10.0.0.1");
+    assert!(matches!(limiter.check_failures("10.0.0.1"), Err(AppError::RateLimited(60))));
+    assert!(limiter.check_failures("10.0.0.2").is_ok());
+    // failures do not use the search limit of the IP
+    assert!(limiter.check("/search", "10.0.0.1", None).is_ok());
+  }
+
+  #[test]
+  fn client_keys_and_ips_have_separate_buckets() {
+    let limiter = limiter(Limit { per_minute: 1.0, burst: 1.0 });
+    assert!(limiter.check("/search", "10.0.0.1", None).is_ok());
+    assert!(limiter.check("/search", "10.0.0.1", None).is_err());
+    assert!(limiter.check("/search", "10.0.0.1", Some("k1")).is_ok());
+    assert!(limiter.check("/search", "10.0.0.2", None).is_ok());
+    assert!(limiter.check("/suggest", "10.0.0.1", None).is_ok());
+    assert!(limiter.check("/exclusions", "10.0.0.1", None).is_ok());
+  }
+}
@@ -1,11 +1,13 @@
-use serde_json::json;
+use std::net::SocketAddr;
+use serde_json::{json, Value};
 use axum::{
     response::IntoResponse,
-    http::StatusCode,
-    extract,
+    http::{HeaderMap, StatusCode},
+    extract::{self, rejection::{JsonRejection, QueryRejection}, ConnectInfo, Path, State},
+    Extension,
     Json,
 };
-use crate::{search::{get_search_results, get_suggest_results}, options::*, exclusions::get_exclusion_patterns, cache::{redis_get_exclusions, redis_set_exclusions}};
+use crate::{clients::ClientKey, errors::AppError, state::AppState, search::{get_search_results, get_suggest_results}, options::*, exclusions::{get_exclusion_patterns, exclusion_status, has_exclusion_profile, reload_exclusion_patterns, edit_exclusion_patterns, PatternEdit, UrlPattern, DEFAULT_PROFILE}, cache::cache_get_exclusions, quota::{METERS, meter_usage}, rate_limit::client_ip, utils::constant_time_eq};
 
 // use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -13,53 +15,114 @@ pub async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "nothing to see here")
 }
 
-pub async fn search_data_response(params: extract::Query<QueryParams>) -> impl IntoResponse {
-    let mut response = json!({
-        "valid": false,
-    });
-    if params.q.is_some() {
-      let options = BraveSearchOptions::new(&params);
-      let result_set_data = get_search_results(&options).await;
-      if let Ok(result_set) = result_set_data {
-        response = json!(result_set)
-      }
-    }
-    (StatusCode::OK, Json(response))
+fn require_query(params: &QueryParams) -> Result<(), AppError> {
+  match params.q.as_ref() {
+    Some(q) if !q.trim().is_empty() => Ok(()),
+    _ => Err(AppError::MissingQuery)
+  }
 }
 
-pub async fn suggest_data_response(params: extract::Query<QueryParams>) -> impl IntoResponse {
-  let mut response = json!({
-      "valid": false,
-  });
-  if params.q.is_some() {
-    let options = BraveSearchOptions::new(&params);
-    let result_set_data = get_suggest_results(&options).await;
-    if let Ok(result_set) = result_set_data {
-      response = json!(result_set)
-    }
+pub async fn search_data_response(State(state): State<AppState>, client: Option<Extension<ClientKey>>, params: Result<extract::Query<QueryParams>, QueryRejection>) -> Result<Json<Value>, AppError> {
+  let params = params?;
+  require_query(&params)?;
+  let mut options = BraveSearchOptions::new(&params);
+  if let Some(Extension(client)) = client {
+    client.authorize(state.cache.as_ref(), Some(options.mode)).await?;
+    options.set_profile(client.profile.as_deref());
+  }
+  if !has_exclusion_profile(state.cache.as_ref(), &options.profile).await {
+    return Err(AppError::UnknownProfile(options.profile));
+  }
+  let mut result_set = get_search_results(&state, &options).await?;
+  if !params.explain() {
+    result_set.excluded = None;
+  }
+  Ok(Json(json!(result_set)))
+}
+
+pub async fn suggest_data_response(State(state): State<AppState>, client: Option<Extension<ClientKey>>, params: Result<extract::Query<QueryParams>, QueryRejection>) -> Result<Json<Value>, AppError> {
+  let params = params?;
+  require_query(&params)?;
+  let options = BraveSearchOptions::new(&params);
+  if let Some(Extension(client)) = client {
+    client.authorize(state.cache.as_ref(), None).await?;
   }
-  (StatusCode::OK, Json(response))
+  let result_set = get_suggest_results(state.cache.as_ref(), &options).await?;
+  Ok(Json(json!(result_set)))
 }
 
 
-pub async fn list_exclusion_patterns(params: extract::Query<QueryParams>) -> impl IntoResponse {
+/// cached=0 reloads the patterns file before listing, profile=name lists that profile
+pub async fn list_exclusion_patterns(State(state): State<AppState>, params: Result<extract::Query<QueryParams>, QueryRejection>) -> Result<Json<Value>, AppError> {
+  let params = params?;
   let skip_cache = params.cached.unwrap_or(1) < 1;
-  
-  let cached_rows = if skip_cache {
+  if skip_cache {
+    let _ = reload_exclusion_patterns(state.cache.as_ref()).await;
+  }
+  let profile = params.profile.clone().unwrap_or(DEFAULT_PROFILE.to_owned()).trim().to_lowercase();
+  if !has_exclusion_profile(state.cache.as_ref(), &profile).await {
+    return Err(AppError::UnknownProfile(profile));
+  }
+  let status = exclusion_status();
+  let cached_rows = if skip_cache || status.loaded.is_some() {
     vec![]
   } else {
-    redis_get_exclusions()
+    cache_get_exclusions(state.cache.as_ref(), &profile).await
   };
-  let cached = !skip_cache && cached_rows.len() > 0;
+  let cached = !cached_rows.is_empty();
   let items = if cached {
     cached_rows
   } else {
-    let rows = get_exclusion_patterns();
-    if rows.len() > 0 {
-      redis_set_exclusions(&rows);
+    let rows = get_exclusion_patterns(&profile);
+    if rows.is_empty() && !skip_cache {
+      // an empty list may just mean the cache holding it is down
+      state.cache.ping().await?;
     }
     rows
   };
-  let response = json!({"cached": cached, "items": items });
-  (StatusCode::OK, Json(response))
-}
\ No newline at end of file
+  Ok(Json(json!({"cached": cached, "profile": profile, "items": items, "status": status })))
+}
+
+pub async fn add_exclusion_pattern(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, row: Result<Json<UrlPattern>, JsonRejection>) -> Result<Json<Value>, AppError> {
+  require_admin(&state, &headers, &addr)?;
+  let Json(row) = row?;
+  let items = edit_exclusion_patterns(state.cache.as_ref(), PatternEdit::Add(row)).await?;
+  Ok(Json(json!({"valid": true, "items": items, "status": exclusion_status() })))
+}
+
+pub async fn update_exclusion_pattern(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Path(key): Path<String>, row: Result<Json<UrlPattern>, JsonRejection>) -> Result<Json<Value>, AppError> {
+  require_admin(&state, &headers, &addr)?;
+  let Json(row) = row?;
+  let items = edit_exclusion_patterns(state.cache.as_ref(), PatternEdit::Update(key, row)).await?;
+  Ok(Json(json!({"valid": true, "items": items, "status": exclusion_status() })))
+}
+
+pub async fn delete_exclusion_pattern(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Path(key): Path<String>) -> Result<Json<Value>, AppError> {
+  require_admin(&state, &headers, &addr)?;
+  let items = edit_exclusion_patterns(state.cache.as_ref(), PatternEdit::Remove(key)).await?;
+  Ok(Json(json!({"valid": true, "items": items, "status": exclusion_status() })))
+}
+
+/// Admin routes require the ADMIN_KEY value in the X-Admin-Key header and are disabled without it.
+/// Failed attempts count against the same per-IP limit as unknown client keys.
+fn require_admin(state: &AppState, headers: &HeaderMap, addr: &SocketAddr) -> Result<(), AppError> {
+  let ip = client_ip(headers, addr);
+  state.limiter.check_failures(&ip)?;
+  let admin_key = dotenv::var("ADMIN_KEY").unwrap_or_default();
+  let sent_key = headers.get("x-admin-key").and_then(|v| v.to_str().ok()).unwrap_or("");
+  if !admin_key.is_empty() && constant_time_eq(sent_key, &admin_key) {
+    Ok(())
+  } else {
+    state.limiter.record_failure(&ip);
+    Err(AppError::Forbidden)
+  }
+}
+
+pub async fn admin_usage(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap) -> Result<Json<Value>, AppError> {
+  require_admin(&state, &headers, &addr)?;
+  let mut meters = Vec::new();
+  for meter in METERS {
+    meters.push(meter_usage(state.cache.as_ref(), meter).await);
+  }
+  Ok(Json(json!({"store": state.cache.name(), "meters": meters })))
+}
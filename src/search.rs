@@ -1,100 +1,155 @@
 use chrono::Duration;
-use reqwest::Error;
-use serde_json;
+use futures::future::join_all;
+use tokio::time::timeout;
 
-use crate::{models::{ResultSet, AutoSuggestResultSet}, constants::{BRAVE_SEARCH_BASE, BRAVE_SUGGEST_BASE, MOJEEK_SEARCH_BASE}, cache::{redis_get_results, redis_set_results, redis_get_suggest_results, redis_set_suggest_results}, options::{BraveSearchOptions, SearchProvider}, utils::build_query_string};
+use crate::{errors::AppError, state::AppState, models::{ResultSet, AutoSuggestResultSet, ProviderStatus}, constants::BRAVE_SUGGEST_BASE, stores::CacheStore, cache::{cache_get_results, cache_set_results, cache_set_partial_results, cache_get_suggest_results, cache_set_suggest_results}, options::{BraveSearchOptions, SearchProvider}, utils::build_query_string, backends::{backend_registry, fetch_json}, exclusions::get_exclusion_matcher, quota::{is_exhausted, record_call}};
 
-pub async fn fetch_search_results(options: &BraveSearchOptions) -> Result<ResultSet, Error> {
-  let uri = [BRAVE_SEARCH_BASE, &build_query_string(&options.to_tuples())].concat();
-  let api_key = dotenv::var("BRAVE_SEARCH").unwrap_or("".to_string());
-  let client = reqwest::Client::new();
-  
-  let result = client.get(&uri).header("X-Subscription-Token", &api_key).send().await;
-  match result {
-      Ok(resp) => {
-        let result  = resp.json::<serde_json::Value>().await;
-        match result {
-          Ok(json) => Ok(ResultSet::new(&json, options)),
-          Err(err) => Err(err)
+const SEARCH_CACHE_MINUTES: i64 = 60;
+
+const SUGGEST_CACHE_MINUTES: i64 = 1440;
+
+pub async fn fetch_search_results(store: &dyn CacheStore, options: &BraveSearchOptions) -> Result<ResultSet, AppError> {
+  let (selected, unpaged) = backend_registry().for_options(options);
+  let mut backends = Vec::new();
+  let mut exhausted = Vec::new();
+  for backend in selected {
+    if is_exhausted(store, backend.name()).await {
+      exhausted.push(backend.provider());
+    } else {
+      backends.push(backend);
+    }
+  }
+  if backends.is_empty() && !exhausted.is_empty() {
+    return Err(AppError::QuotaExhausted(exhausted));
+  }
+  // report skipped providers, so that an empty result for a later page is explained
+  let mut statuses: Vec<ProviderStatus> = exhausted.into_iter().map(ProviderStatus::quota_exhausted)
+    .chain(unpaged.iter().map(|backend| ProviderStatus::paging_unsupported(backend.provider())))
+    .collect();
+  for backend in backends.iter() {
+    record_call(store, backend.name()).await;
+  }
+  let requests = backends.iter().map(|backend| timeout(backend.timeout(), backend.search(options)));
+  let responses = join_all(requests).await;
+  let mut result_sets: Vec<ResultSet> = Vec::new();
+  let mut first_error: Option<AppError> = None;
+  for (backend, response) in backends.iter().zip(responses) {
+    match response {
+      Ok(Ok(result)) => {
+        statuses.push(ProviderStatus::ok(backend.provider(), result.count));
+        result_sets.push(result);
+      },
+      Ok(Err(error)) => {
+        tracing::warn!("{} search failed: {}", backend.name(), error);
+        statuses.push(ProviderStatus::error(backend.provider(), &error.to_string()));
+        if first_error.is_none() {
+          first_error = Some(error);
         }
       },
-      Err(error) => Err(error)
+      Err(_elapsed) => {
+        tracing::warn!("{} search timed out after {:?}", backend.name(), backend.timeout());
+        statuses.push(ProviderStatus::timeout(backend.provider(), backend.timeout()));
+        if first_error.is_none() {
+          first_error = Some(AppError::UpstreamTimeout(backend.provider()));
+        }
+      }
+    }
+  }
+  // partial results from the providers that responded are better than none
+  let mut result_sets = result_sets.into_iter();
+  match (result_sets.next(), first_error) {
+    (Some(mut result), _) => {
+      result.merge_results(result_sets.collect(), options.merge);
+      result.set_provider_statuses(statuses);
+      Ok(result)
+    },
+    // nothing came back, so report why the first provider failed
+    (None, Some(error)) => Err(error),
+    (None, None) => {
+      let mut result = ResultSet::empty();
+      result.set_provider_statuses(statuses);
+      Ok(result)
+    }
   }
 }
 
-pub async fn fetch_search_results_mojeek(options: &BraveSearchOptions) -> Result<ResultSet, Error> {
-  let uri = [MOJEEK_SEARCH_BASE, &build_query_string(&options.to_mojeek_tuples())].concat();
-  let client = reqwest::Client::new();
-  
-  let result = client.get(&uri).send().await;
-  
-  match result {
-      Ok(resp) => {
-        let result  = resp.json::<serde_json::Value>().await;
-        match result {
-          Ok(json) => Ok(ResultSet::new_from_mojeek(&json, options)),
-          Err(err) => Err(err)
-        }
-      },
-      Err(error) => Err(error)
+async fn refresh_search_results(store: &dyn CacheStore, options: &BraveSearchOptions, key: &str) -> Result<ResultSet, AppError> {
+  let result = fetch_search_results(store, options).await?;
+  // partial results, including those with providers skipped for quota, are kept briefly
+  // so that missing providers are retried soon without refetching on every request
+  if result.valid && result.all_providers_ok() {
+    cache_set_results(store, key, &result.clone(), Duration::minutes(SEARCH_CACHE_MINUTES)).await;
+  } else if result.valid {
+    cache_set_partial_results(store, key, &result.clone(), Duration::minutes(SEARCH_CACHE_MINUTES)).await;
+  }
+  Ok(result)
+}
+
+/// Fetch and cache fresh results, sharing one upstream fetch between identical concurrent requests
+async fn coalesced_refresh(state: &AppState, options: &BraveSearchOptions, key: &str) -> Result<ResultSet, AppError> {
+  let store = state.cache.clone();
+  let options = options.clone();
+  let owned_key = key.to_owned();
+  state.coalesce(key, move || Box::pin(async move {
+    refresh_search_results(store.as_ref(), &options, &owned_key).await
+  })).await
+}
+
+/// Refresh a stale cache entry after the stale copy has been served
+fn revalidate_in_background(state: &AppState, options: &BraveSearchOptions, key: String) {
+  if state.is_in_flight(&key) {
+    return;
   }
+  let state = state.clone();
+  let options = options.clone();
+  tokio::spawn(async move {
+    if let Err(error) = coalesced_refresh(&state, &options, &key).await {
+      tracing::warn!("background refresh of {} failed: {}", key, error);
+    }
+  });
 }
 
-pub async fn get_search_results(options: &BraveSearchOptions) -> Result<ResultSet, Error> {
+/// Results are cached as merged, so that rule edits and reloads apply to cached queries too
+async fn apply_profile_rules(store: &dyn CacheStore, options: &BraveSearchOptions, mut result: ResultSet) -> ResultSet {
+  result.apply_rules(get_exclusion_matcher(store, &options.profile).await.as_ref());
+  result.fold_near_duplicates(options.fold);
+  result
+}
+
+pub async fn get_search_results(state: &AppState, options: &BraveSearchOptions) -> Result<ResultSet, AppError> {
   let key = options.to_cache_key(options.mode);
-  if let Some(result) = redis_get_results(&key, Duration::minutes(60)) {
-    Ok(result)
-  } else {
-    let result_set = fetch_search_results(options).await;
-    if let Ok(mut result) = result_set {
-      if options.mode.search_mojeek() {
-        let result_set_mojeek = fetch_search_results_mojeek(options).await;
-        if let Ok(result2) = result_set_mojeek {
-          result.merge_results(result2);
-        }
-      }
-      result.exclude_by_patterns();
-      if result.valid {
-        redis_set_results(&key, &result.clone());
-      }
-      Ok(result)
-    } else {
-      result_set
+  let result = if let Some(result) = cache_get_results(state.cache.as_ref(), &key, Duration::minutes(SEARCH_CACHE_MINUTES)).await {
+    if result.stale {
+      revalidate_in_background(state, options, key);
     }
-  }
+    result
+  } else {
+    coalesced_refresh(state, options, &key).await?
+  };
+  Ok(apply_profile_rules(state.cache.as_ref(), options, result).await)
 }
 
-pub async fn fetch_suggest_results(options: &BraveSearchOptions) -> Result<AutoSuggestResultSet, Error> {
+pub async fn fetch_suggest_results(store: &dyn CacheStore, options: &BraveSearchOptions) -> Result<AutoSuggestResultSet, AppError> {
+  if is_exhausted(store, "brave_suggest").await {
+    return Err(AppError::QuotaExhausted(vec![SearchProvider::Brave]));
+  }
+  record_call(store, "brave_suggest").await;
   let uri = [BRAVE_SUGGEST_BASE, &build_query_string(&options.to_suggest_tuples())].concat();
   let api_key = dotenv::var("BRAVE_SUGGEST").unwrap_or("".to_string());
   let client = reqwest::Client::new();
-  
-  let result = client.get(&uri).header("X-Subscription-Token", &api_key).send().await;
-  match result {
-      Ok(resp) => {
-        let result  = resp.json::<serde_json::Value>().await;
-        match result {
-          Ok(json) => Ok(AutoSuggestResultSet::new(&json, options)),
-          Err(err) => Err(err)
-        }
-      },
-      Err(error) => Err(error)
-  }
+  let json = fetch_json(SearchProvider::Brave, "brave_suggest", client.get(&uri).header("X-Subscription-Token", &api_key)).await?;
+  Ok(AutoSuggestResultSet::new(&json, options))
 }
 
-pub async fn get_suggest_results(options: &BraveSearchOptions) -> Result<AutoSuggestResultSet, Error> {
+pub async fn get_suggest_results(store: &dyn CacheStore, options: &BraveSearchOptions) -> Result<AutoSuggestResultSet, AppError> {
   let key = options.to_suggest_cache_key();
-  if let Some(result) = redis_get_suggest_results(&key, Duration::minutes(1440)) {
+  if let Some(result) = cache_get_suggest_results(store, &key, Duration::minutes(SUGGEST_CACHE_MINUTES)).await {
     Ok(result)
   } else {
-    let result_set = fetch_suggest_results(options).await;
-    if let Ok(result) = result_set {
-      if result.valid {
-        redis_set_suggest_results(&key, &result.clone());
-      }
-      Ok(result)
-    } else {
-      result_set
+    let result = fetch_suggest_results(store, options).await?;
+    if result.valid {
+      cache_set_suggest_results(store, &key, &result.clone(), Duration::minutes(SUGGEST_CACHE_MINUTES)).await;
     }
+    Ok(result)
   }
 }
\ No newline at end of file
@@ -0,0 +1,82 @@
+use std::{collections::HashSet, hash::{DefaultHasher, Hash, Hasher}};
+
+/// Number of consecutive words in each shingle
+const SHINGLE_SIZE: usize = 3;
+
+/// Text with HTML tags such as Brave's <strong> highlights replaced by a separator, if any
+fn replace_tags(text: &str, separator: Option<char>) -> String {
+  let mut plain = String::with_capacity(text.len());
+  let mut in_tag = false;
+  for c in text.chars() {
+    match c {
+      '<' => in_tag = true,
+      '>' if in_tag => {
+        in_tag = false;
+        if let Some(separator) = separator {
+          plain.push(separator);
+        }
+      },
+      _ if !in_tag => plain.push(c),
+      _ => ()
+    }
+  }
+  plain
+}
+
+/// Text with HTML tags removed, so that highlighted phrases read as they would on the page
+pub fn strip_tags(text: &str) -> String {
+  replace_tags(text, None)
+}
+
+/// Lowercase words with HTML tags removed
+fn plain_words(text: &str) -> Vec<String> {
+  replace_tags(text, Some(' ')).split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).map(|w| w.to_lowercase()).collect()
+}
+
+/// Hashed word shingles of a text. Short texts fall back to single words.
+pub fn shingles(text: &str) -> HashSet<u64> {
+  let words = plain_words(text);
+  let size = if words.len() < SHINGLE_SIZE { 1 } else { SHINGLE_SIZE };
+  words.windows(size).map(|window| {
+    let mut hasher = DefaultHasher::new();
+    window.hash(&mut hasher);
+    hasher.finish()
+  }).collect()
+}
+
+/// Jaccard similarity of two shingle sets, between 0 and 1
+pub fn jaccard(a: &HashSet<u64>, b: &HashSet<u64>) -> f64 {
+  if a.is_empty() || b.is_empty() {
+    return 0.0;
+  }
+  let shared = a.intersection(b).count();
+  shared as f64 / (a.len() + b.len() - shared) as f64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn shingles_ignore_case_punctuation_and_highlights() {
+    assert_eq!(shingles("Storm hits the <strong>coast</strong> tonight"), shingles("storm hits the coast, tonight!"));
+    assert_eq!(shingles("Storm hits the coast tonight").len(), 3);
+  }
+
+  #[test]
+  fn short_texts_fall_back_to_words() {
+    assert_eq!(shingles("Storm warning").len(), 2);
+    assert!(shingles("<b></b>").is_empty());
+  }
+
+  #[test]
+  fn jaccard_measures_shared_shingles() {
+    let a = shingles("one two three four five");
+    let b = shingles("one two three four six");
+    assert_eq!(jaccard(&a, &a), 1.0);
+    // two shared out of four distinct shingles
+    assert_eq!(jaccard(&a, &b), 0.5);
+    assert_eq!(jaccard(&a, &shingles("seven eight nine")), 0.0);
+    assert_eq!(jaccard(&a, &HashSet::new()), 0.0);
+  }
+}
@@ -0,0 +1,95 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use crate::{clients::ClientKeys, errors::AppError, models::ResultSet, rate_limit::RateLimiter, stores::{CacheStore, store_from_env}};
+
+type SharedFetch = Shared<BoxFuture<'static, Result<ResultSet, AppError>>>;
+
+/// Removes the in-flight entry of a fetch however its task ends, including by a panic
+struct InFlightGuard {
+  registry: Arc<Mutex<HashMap<String, SharedFetch>>>,
+  key: String,
+}
+
+impl Drop for InFlightGuard {
+  fn drop(&mut self) {
+    self.registry.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.key);
+  }
+}
+
+/// Shared state handed to every route handler
+#[derive(Clone)]
+pub struct AppState {
+  pub cache: Arc<dyn CacheStore>,
+  pub limiter: Arc<RateLimiter>,
+  pub clients: Arc<ClientKeys>,
+  in_flight: Arc<Mutex<HashMap<String, SharedFetch>>>,
+}
+
+impl AppState {
+  pub fn from_env() -> Self {
+    AppState {
+      cache: store_from_env(),
+      limiter: Arc::new(RateLimiter::from_env()),
+      clients: Arc::new(ClientKeys::from_env()),
+      in_flight: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  pub fn is_in_flight(&self, key: &str) -> bool {
+    self.in_flight.lock().unwrap_or_else(|e| e.into_inner()).contains_key(key)
+  }
+
+  /// Join the upstream fetch already running for the cache key, or start one.
+  /// Concurrent identical requests all receive the result of a single fetch.
+  /// The fetch runs as its own task, so it completes even if every caller goes away.
+  pub async fn coalesce<F>(&self, key: &str, fetch: F) -> Result<ResultSet, AppError>
+  where F: FnOnce() -> BoxFuture<'static, Result<ResultSet, AppError>> {
+    let shared = {
+      let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+      match in_flight.get(key) {
+        Some(existing) => existing.clone(),
+        None => {
+          let guard = InFlightGuard { registry: self.in_flight.clone(), key: key.to_owned() };
+          let future = fetch();
+          // the entry is inserted below before this lock is released, so the task cannot remove it first
+          let handle = tokio::spawn(async move {
+            let _guard = guard;
+            future.await
+          });
+          let shared = async move {
+            handle.await.unwrap_or_else(|error| Err(AppError::Internal(error.to_string())))
+          }.boxed().shared();
+          in_flight.insert(key.to_owned(), shared.clone());
+          shared
+        }
+      }
+    };
+    shared.await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::stores::MemoryStore;
+  use super::*;
+
+  fn state() -> AppState {
+    AppState {
+      cache: Arc::new(MemoryStore::new(10, None)),
+      limiter: Arc::new(RateLimiter::from_env()),
+      clients: Arc::new(ClientKeys::from_env()),
+      in_flight: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  #[tokio::test]
+  async fn a_panicking_fetch_does_not_block_the_key() {
+    let state = state();
+    let result = state.coalesce("key", || Box::pin(async { panic!("fetch failed") })).await;
+    assert!(matches!(result, Err(AppError::Internal(_))));
+    assert!(!state.is_in_flight("key"));
+    let result = state.coalesce("key", || Box::pin(async { Ok(ResultSet::empty()) })).await;
+    assert!(result.is_ok());
+    assert!(!state.is_in_flight("key"));
+  }
+}
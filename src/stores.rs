@@ -0,0 +1,406 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use futures::future::BoxFuture;
+use redis::{AsyncCommands, RedisResult, Client, aio::ConnectionManager};
+use tokio::sync::OnceCell;
+
+use crate::errors::AppError;
+
+/// Key-value store for serialized result sets and lists.
+/// A ttl of None keeps the entry until it is overwritten or evicted.
+pub trait CacheStore: Send + Sync {
+  fn name(&self) -> &'static str;
+
+  fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, AppError>>;
+
+  fn set<'a>(&'a self, key: &'a str, value: String, ttl: Option<Duration>) -> BoxFuture<'a, Result<(), AppError>>;
+
+  /// Increment a counter, setting the ttl when the counter is created
+  fn incr<'a>(&'a self, key: &'a str, ttl: Option<Duration>) -> BoxFuture<'a, Result<u64, AppError>>;
+
+  /// Read a counter written by incr, bypassing any local copy that may lag behind it
+  fn get_counter<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, AppError>> {
+    self.get(key)
+  }
+
+  fn ping(&self) -> BoxFuture<'_, Result<(), AppError>>;
+}
+
+/// Shared async Redis connection, opened on first use from REDIS_URL.
+/// Use rediss:// for TLS; auth and db index go in the URL, e.g. redis://:pass@host:6379/2
+#[derive(Clone)]
+pub struct RedisStore {
+  client: Option<Client>,
+  manager: Arc<OnceCell<ConnectionManager>>,
+}
+
+impl RedisStore {
+  pub fn from_env() -> Self {
+    let url = dotenv::var("REDIS_URL").unwrap_or("redis://127.0.0.1/".to_owned());
+    let client = match Client::open(url) {
+      Ok(client) => Some(client),
+      Err(error) => {
+        tracing::error!("invalid REDIS_URL: {}", error);
+        None
+      }
+    };
+    RedisStore {
+      client,
+      manager: Arc::new(OnceCell::new()),
+    }
+  }
+
+  /// A failed attempt leaves the cell empty so the next call retries
+  pub async fn connection(&self) -> RedisResult<ConnectionManager> {
+    let Some(client) = self.client.clone() else {
+      return Err((redis::ErrorKind::InvalidClientConfig, "invalid REDIS_URL").into());
+    };
+    let manager = self.manager.get_or_try_init(|| ConnectionManager::new(client)).await?;
+    Ok(manager.clone())
+  }
+
+  /// Value with its remaining ttl, None if the key does not expire
+  async fn get_with_ttl(&self, key: &str) -> Result<(Option<String>, Option<Duration>), AppError> {
+    let mut connection = self.connection().await?;
+    let (value, pttl): (Option<String>, i64) = redis::pipe().get(key).cmd("PTTL").arg(key).query_async(&mut connection).await?;
+    // PTTL is -1 for keys without a ttl and -2 for missing keys
+    let ttl = u64::try_from(pttl).ok().map(Duration::from_millis);
+    Ok((value, ttl))
+  }
+}
+
+impl CacheStore for RedisStore {
+  fn name(&self) -> &'static str {
+    "redis"
+  }
+
+  fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, AppError>> {
+    Box::pin(async move {
+      let mut connection = self.connection().await?;
+      let value: Option<String> = connection.get(key).await?;
+      Ok(value)
+    })
+  }
+
+  fn set<'a>(&'a self, key: &'a str, value: String, ttl: Option<Duration>) -> BoxFuture<'a, Result<(), AppError>> {
+    Box::pin(async move {
+      let mut connection = self.connection().await?;
+      match ttl {
+        Some(ttl) => connection.set_ex::<_, _, ()>(key, value, ttl.as_secs().max(1) as usize).await?,
+        None => connection.set::<_, _, ()>(key, value).await?,
+      }
+      Ok(())
+    })
+  }
+
+  fn incr<'a>(&'a self, key: &'a str, ttl: Option<Duration>) -> BoxFuture<'a, Result<u64, AppError>> {
+    Box::pin(async move {
+      let mut connection = self.connection().await?;
+      let count: u64 = connection.incr(key, 1).await?;
+      if let (1, Some(ttl)) = (count, ttl) {
+        connection.expire::<_, ()>(key, ttl.as_secs().max(1) as usize).await?;
+      }
+      Ok(count)
+    })
+  }
+
+  fn ping(&self) -> BoxFuture<'_, Result<(), AppError>> {
+    Box::pin(async move {
+      let mut connection = self.connection().await?;
+      redis::cmd("PING").query_async::<_, String>(&mut connection).await?;
+      Ok(())
+    })
+  }
+}
+
+struct MemoryEntry {
+  value: String,
+  expires: Option<Instant>,
+  tick: u64,
+}
+
+#[derive(Default)]
+struct MemoryEntries {
+  entries: HashMap<String, MemoryEntry>,
+  // least recently used keys first
+  order: BTreeMap<u64, String>,
+  tick: u64,
+}
+
+impl MemoryEntries {
+  fn touch(&mut self, key: &str) -> u64 {
+    self.tick += 1;
+    let tick = self.tick;
+    if let Some(entry) = self.entries.get_mut(key) {
+      self.order.remove(&entry.tick);
+      entry.tick = tick;
+      self.order.insert(tick, key.to_owned());
+    }
+    tick
+  }
+
+  fn remove(&mut self, key: &str) {
+    if let Some(entry) = self.entries.remove(key) {
+      self.order.remove(&entry.tick);
+    }
+  }
+}
+
+/// In-process LRU store bounded by item count and, optionally, by a maximum entry age
+pub struct MemoryStore {
+  capacity: usize,
+  max_ttl: Option<Duration>,
+  inner: Mutex<MemoryEntries>,
+}
+
+impl MemoryStore {
+  pub fn new(capacity: usize, max_ttl: Option<Duration>) -> Self {
+    MemoryStore {
+      capacity: capacity.max(1),
+      max_ttl,
+      inner: Mutex::new(MemoryEntries::default()),
+    }
+  }
+
+  fn get_value(&self, key: &str) -> Option<String> {
+    let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    let expired = inner.entries.get(key)?.expires.is_some_and(|exp| exp <= Instant::now());
+    if expired {
+      inner.remove(key);
+      return None;
+    }
+    inner.touch(key);
+    inner.entries.get(key).map(|entry| entry.value.clone())
+  }
+
+  fn set_value(&self, key: &str, value: String, ttl: Option<Duration>) {
+    let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    self.insert(&mut inner, key, value, ttl);
+  }
+
+  fn remove_value(&self, key: &str) {
+    self.inner.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+  }
+
+  /// Add an entry to the locked entries, evicting the least recently used ones beyond capacity
+  fn insert(&self, inner: &mut MemoryEntries, key: &str, value: String, ttl: Option<Duration>) {
+    let ttl = match (ttl, self.max_ttl) {
+      (Some(ttl), Some(max_ttl)) => Some(ttl.min(max_ttl)),
+      (ttl, max_ttl) => ttl.or(max_ttl),
+    };
+    inner.remove(key);
+    while inner.entries.len() >= self.capacity {
+      let Some((_, oldest)) = inner.order.pop_first() else {
+        break;
+      };
+      inner.entries.remove(&oldest);
+    }
+    let entry = MemoryEntry {
+      value,
+      expires: ttl.map(|ttl| Instant::now() + ttl),
+      tick: 0,
+    };
+    inner.entries.insert(key.to_owned(), entry);
+    inner.touch(key);
+  }
+
+  /// Read, increment and write under one lock so that concurrent increments are not lost
+  fn incr_value(&self, key: &str, ttl: Option<Duration>) -> u64 {
+    let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+    let now = Instant::now();
+    let current = inner.entries.get(key)
+      .filter(|entry| entry.expires.is_none_or(|exp| exp > now))
+      .map(|entry| entry.value.parse::<u64>().unwrap_or(0));
+    match current {
+      Some(current) => {
+        let count = current + 1;
+        if let Some(entry) = inner.entries.get_mut(key) {
+          entry.value = count.to_string();
+        }
+        inner.touch(key);
+        count
+      },
+      None => {
+        self.insert(&mut inner, key, "1".to_owned(), ttl);
+        1
+      }
+    }
+  }
+}
+
+impl CacheStore for MemoryStore {
+  fn name(&self) -> &'static str {
+    "memory"
+  }
+
+  fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, AppError>> {
+    Box::pin(async move { Ok(self.get_value(key)) })
+  }
+
+  fn set<'a>(&'a self, key: &'a str, value: String, ttl: Option<Duration>) -> BoxFuture<'a, Result<(), AppError>> {
+    Box::pin(async move {
+      self.set_value(key, value, ttl);
+      Ok(())
+    })
+  }
+
+  fn incr<'a>(&'a self, key: &'a str, ttl: Option<Duration>) -> BoxFuture<'a, Result<u64, AppError>> {
+    Box::pin(async move { Ok(self.incr_value(key, ttl)) })
+  }
+
+  fn ping(&self) -> BoxFuture<'_, Result<(), AppError>> {
+    Box::pin(async move { Ok(()) })
+  }
+}
+
+/// In-memory store in front of Redis. Hot keys are served without a network round-trip.
+pub struct TieredStore {
+  memory: MemoryStore,
+  redis: RedisStore,
+}
+
+impl CacheStore for TieredStore {
+  fn name(&self) -> &'static str {
+    "tiered"
+  }
+
+  fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, AppError>> {
+    Box::pin(async move {
+      if let Some(value) = self.memory.get_value(key) {
+        return Ok(Some(value));
+      }
+      // copies expire with the Redis entry. Keys without a ttl, such as lists that other
+      // instances may edit, are always read from Redis.
+      let (value, ttl) = self.redis.get_with_ttl(key).await?;
+      if let (Some(value), Some(ttl)) = (value.as_ref(), ttl) {
+        self.memory.set_value(key, value.clone(), Some(ttl));
+      }
+      Ok(value)
+    })
+  }
+
+  fn set<'a>(&'a self, key: &'a str, value: String, ttl: Option<Duration>) -> BoxFuture<'a, Result<(), AppError>> {
+    Box::pin(async move {
+      match ttl {
+        Some(_) => self.memory.set_value(key, value.clone(), ttl),
+        None => self.memory.remove_value(key),
+      }
+      self.redis.set(key, value, ttl).await
+    })
+  }
+
+  /// Counters are shared between instances, so they are only kept in Redis
+  fn incr<'a>(&'a self, key: &'a str, ttl: Option<Duration>) -> BoxFuture<'a, Result<u64, AppError>> {
+    self.redis.incr(key, ttl)
+  }
+
+  fn get_counter<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, AppError>> {
+    self.redis.get(key)
+  }
+
+  fn ping(&self) -> BoxFuture<'_, Result<(), AppError>> {
+    self.redis.ping()
+  }
+}
+
+/// Store that keeps nothing, for running without any cache
+pub struct NoopStore;
+
+impl CacheStore for NoopStore {
+  fn name(&self) -> &'static str {
+    "none"
+  }
+
+  fn get<'a>(&'a self, _key: &'a str) -> BoxFuture<'a, Result<Option<String>, AppError>> {
+    Box::pin(async move { Ok(None) })
+  }
+
+  fn set<'a>(&'a self, _key: &'a str, _value: String, _ttl: Option<Duration>) -> BoxFuture<'a, Result<(), AppError>> {
+    Box::pin(async move { Ok(()) })
+  }
+
+  fn incr<'a>(&'a self, _key: &'a str, _ttl: Option<Duration>) -> BoxFuture<'a, Result<u64, AppError>> {
+    Box::pin(async move { Err(AppError::CacheUnavailable("no cache store configured".to_owned())) })
+  }
+
+  fn ping(&self) -> BoxFuture<'_, Result<(), AppError>> {
+    Box::pin(async move { Ok(()) })
+  }
+}
+
+fn env_number(key: &str) -> Option<u64> {
+  dotenv::var(key).ok().and_then(|v| v.parse::<u64>().ok())
+}
+
+/// Store selected by CACHE_BACKEND: redis (default), memory, tiered or none.
+/// CACHE_MEMORY_ITEMS and CACHE_MEMORY_SECS bound the in-memory store.
+pub fn store_from_env() -> Arc<dyn CacheStore> {
+  let backend = dotenv::var("CACHE_BACKEND").unwrap_or("redis".to_owned()).to_lowercase();
+  let capacity = env_number("CACHE_MEMORY_ITEMS").unwrap_or(1000) as usize;
+  let max_ttl = env_number("CACHE_MEMORY_SECS").map(Duration::from_secs);
+  let store: Arc<dyn CacheStore> = match backend.as_str() {
+    "memory" | "lru" => Arc::new(MemoryStore::new(capacity, max_ttl)),
+    "tiered" | "memory+redis" => Arc::new(TieredStore {
+      // keep the memory tier short-lived so that it does not drift far from Redis
+      memory: MemoryStore::new(capacity, max_ttl.or(Some(Duration::from_secs(300)))),
+      redis: RedisStore::from_env(),
+    }),
+    "none" | "noop" | "off" => Arc::new(NoopStore),
+    _ => Arc::new(RedisStore::from_env()),
+  };
+  tracing::info!("using {} cache store", store.name());
+  store
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn evicts_least_recently_used_entry() {
+    let store = MemoryStore::new(2, None);
+    store.set_value("a", "1".to_owned(), None);
+    store.set_value("b", "2".to_owned(), None);
+    // reading a makes b the least recently used entry
+    assert_eq!(store.get_value("a"), Some("1".to_owned()));
+    store.set_value("c", "3".to_owned(), None);
+    assert_eq!(store.get_value("b"), None);
+    assert_eq!(store.get_value("a"), Some("1".to_owned()));
+    assert_eq!(store.get_value("c"), Some("3".to_owned()));
+  }
+
+  #[test]
+  fn overwriting_does_not_evict() {
+    let store = MemoryStore::new(2, None);
+    store.set_value("a", "1".to_owned(), None);
+    store.set_value("b", "2".to_owned(), None);
+    store.set_value("a", "3".to_owned(), None);
+    assert_eq!(store.get_value("a"), Some("3".to_owned()));
+    assert_eq!(store.get_value("b"), Some("2".to_owned()));
+  }
+
+  #[test]
+  fn expired_entries_are_not_served() {
+    let store = MemoryStore::new(10, Some(Duration::from_millis(1)));
+    store.set_value("a", "1".to_owned(), None);
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(store.get_value("a"), None);
+    assert_eq!(store.incr_value("a", None), 1);
+  }
+
+  #[test]
+  fn concurrent_increments_are_all_counted() {
+    let store = Arc::new(MemoryStore::new(10, None));
+    let handles = (0..8).map(|_| {
+      let store = store.clone();
+      std::thread::spawn(move || {
+        for _ in 0..250 {
+          store.incr_value("count", None);
+        }
+      })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+      handle.join().unwrap();
+    }
+    assert_eq!(store.get_value("count"), Some("2000".to_owned()));
+  }
+}
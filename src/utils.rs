@@ -1,27 +1,103 @@
+use url::Url;
 use urlencoding::encode;
-use string_patterns::*;
 
 pub fn build_query_string(options: &[(&str, String)]) -> String {
   let mut params: Vec<String> = Vec::new();
   for pair in options {
     let (key, value) = pair;
-    params.push([*key, encode(&value).to_string().as_str()].join("="));
+    params.push([*key, encode(value).to_string().as_str()].join("="));
   }
-  if params.len() > 0 {
+  if !params.is_empty() {
     format!("?{}", params.join("&"))
   } else {
-    return "".to_owned()
+    "".to_owned()
   }
 }
 
-pub fn build_query_option<'a>(key: &'a str, value: String) -> (&'a str, String) {
-  (key, value)
+pub fn find_position_in_strings(strings: &[String], sample: &str) -> Option<usize> {
+  strings.iter().position(|u| *u == sample)
 }
 
-pub fn find_position_in_strings(strings: &[String], sample: &str) -> Option<usize> {
-  strings.into_iter().position(|u| u.to_owned() == sample.to_string())
+const TRACKING_PARAMS: [&str; 14] = [
+  "fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid",
+  "mc_eid", "_ga", "_hsenc", "_hsmi", "ref_src", "spm", "amp"
+];
+
+const MOBILE_HOST_PREFIXES: [&str; 4] = ["www.", "m.", "mobile.", "amp."];
+
+fn is_tracking_param(key: &str, value: &str) -> bool {
+  let lc_key = key.to_lowercase();
+  lc_key.starts_with("utm_") || TRACKING_PARAMS.contains(&lc_key.as_str()) || (lc_key == "outputtype" && value == "amp")
+}
+
+/// Normalised form of a URI used only to detect duplicates across providers.
+/// Scheme, www/mobile/AMP host variants, default ports, tracking parameters,
+/// fragments and trailing slashes are ignored.
+pub fn canonical_url(uri: &str) -> String {
+  let Ok(url) = Url::parse(uri.trim()) else {
+    return uri.trim().to_lowercase();
+  };
+  let mut host = url.host_str().unwrap_or("").to_lowercase();
+  while let Some(prefix) = MOBILE_HOST_PREFIXES.iter().find(|p| host.starts_with(*p) && host.len() > p.len()) {
+    host = host[prefix.len()..].to_string();
+  }
+  let port = match url.port() {
+    Some(80) | Some(443) | None => "".to_owned(),
+    Some(p) => format!(":{}", p),
+  };
+  let segments = url.path().split('/').filter(|s| !s.is_empty() && !s.eq_ignore_ascii_case("amp")).collect::<Vec<&str>>();
+  let path = segments.join("/");
+  let path = path.strip_suffix(".amp").or(path.strip_suffix(".amp.html")).unwrap_or(&path);
+  let mut params = url.query_pairs()
+    .filter(|(k, v)| !is_tracking_param(k, v))
+    .map(|(k, v)| [k, v].join("="))
+    .collect::<Vec<String>>();
+  params.sort();
+  let query = if params.is_empty() { "".to_owned() } else { format!("?{}", params.join("&")) };
+  format!("{}{}/{}{}", host, port, path, query)
+}
+
+/// Compare secrets in time that depends only on their lengths, not on where they differ
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+  a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
-pub fn uri_is_excluded(patterns: &[String], uri: &str) -> bool {
-  patterns.into_iter().any(|p| uri.to_owned().pattern_match(p, true))
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn compares_secrets() {
+    assert!(constant_time_eq("secret", "secret"));
+    assert!(!constant_time_eq("secret", "secreT"));
+    assert!(!constant_time_eq("secret", "secret2"));
+    assert!(!constant_time_eq("", "secret"));
+  }
+
+  #[test]
+  fn variants_of_a_page_share_one_key() {
+    let key = canonical_url("https://example.com/news/story");
+    for uri in [
+      "http://example.com/news/story",
+      "https://www.example.com/news/story",
+      "https://m.example.com/news/story/",
+      "https://EXAMPLE.com:443/news/story#comments",
+      "https://example.com/news/story?utm_source=feed&utm_medium=rss",
+      "https://example.com/amp/news/story.amp",
+    ] {
+      assert_eq!(canonical_url(uri), key, "{}", uri);
+    }
+  }
+
+  #[test]
+  fn other_parameters_are_kept_in_order() {
+    assert_eq!(canonical_url("https://example.com/search?q=a&fbclid=x&page=2"), canonical_url("https://example.com/search?page=2&q=a"));
+    assert_ne!(canonical_url("https://example.com/search?q=a"), canonical_url("https://example.com/search?q=b"));
+  }
+
+  #[test]
+  fn distinct_pages_keep_distinct_keys() {
+    assert_ne!(canonical_url("https://example.com/a"), canonical_url("https://example.com/b"));
+    assert_ne!(canonical_url("https://example.com:8080/a"), canonical_url("https://example.com/a"));
+  }
+}